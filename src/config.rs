@@ -1,20 +1,361 @@
 #[derive(serde::Deserialize)]
 pub struct Config {
+    /// Always `VERSION` once loaded, older configs are migrated first.
+    pub version: u64,
     pub rt_proto: u8,
     pub interface: String,
+    pub vrf: Option<u32>,
+    pub route: Option<RouteAttributes>,
+    /// Public IPv4 space routed to this host. Whatever of it isn't routed to
+    /// a VPS gets a route of the `unassigned_route` kind, so it doesn't loop
+    /// back upstream.
+    #[serde(default)]
+    pub owned_prefixes: Vec<Ipv4Prefix>,
+    #[serde(default)]
+    pub unassigned_route: UnassignedRoute,
+    /// Answer ARP and NDP on `interface` for the public addresses of every VPS,
+    /// for upstreams that put them on-link instead of routing them here.
+    /// The sysctls this sets on `interface` are left as they are when it's
+    /// turned off again.
+    #[serde(default)]
+    pub proxy_neighbours: bool,
+    /// Sysctls of every VPS interface, as `ipv4.rp_filter` for
+    /// `net.ipv4.conf.<interface>.rp_filter`.
+    #[serde(default)]
+    pub sysctls: std::collections::BTreeMap<String, SysctlValue>,
+    /// Anything the templates need beyond the VPSes, such as resolvers,
+    /// available to them as `vars`.
+    #[serde(default)]
+    pub template_vars: serde_json::Map<String, serde_json::Value>,
+    /// Templates to render besides the ones for radvd and kea.
+    #[serde(default)]
+    pub outputs: Vec<TemplateOutput>,
+    /// Empty when the VPSes come from `--config-dir`.
+    #[serde(default)]
     pub vps: Vec<VPS>
 }
 
+/// A template rendered into a file, and how whatever reads it is told about
+/// changes.
+#[derive(serde::Deserialize, Clone)]
+pub struct TemplateOutput {
+    /// Name of one of the templates loaded from `--templates`.
+    pub template: String,
+    pub output: std::path::PathBuf,
+    /// Program and arguments to run once the file has changed.
+    pub reload_command: Option<Vec<String>>,
+    /// Daemon run by us to reload once the file has changed.
+    pub signal_daemon: Option<Daemon>,
+}
+
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Daemon {
+    Radvd,
+    Kea,
+}
+
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum UnassignedRoute {
+    /// Drop traffic silently.
+    #[default]
+    Blackhole,
+    /// Drop traffic and answer with an ICMP unreachable.
+    Unreachable,
+}
+
+#[allow(clippy::upper_case_acronyms)]
 #[derive(serde::Deserialize, serde::Serialize)]
 pub struct VPS {
-    pub vlan: u16,
+    pub vlan: Option<u16>,
+    pub svlan: Option<u16>,
+    pub vxlan: Option<Vxlan>,
+    pub name: Option<String>,
+    pub interface: Option<String>,
+    pub vrf: Option<u32>,
+    pub route: Option<RouteAttributes>,
+    pub mtu: Option<u32>,
+    pub mac: Option<MacAddress>,
+    /// Sysctls of the interface, on top of the global ones.
+    #[serde(default)]
+    pub sysctls: std::collections::BTreeMap<String, SysctlValue>,
+    /// Limit on traffic from the VPS, in bits per second.
+    pub ingress_rate: Option<u64>,
+    /// Limit on traffic to the VPS, in bits per second.
+    pub egress_rate: Option<u64>,
+    /// A disabled VPS keeps its interface and address, but the link is down.
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub state: VPSState,
     pub v4_addr: std::net::Ipv4Addr,
     pub v4_public: Option<V4Ip>,
     pub v6_prefix: std::net::Ipv6Addr,
+    /// Addresses out of `v6_prefix` to answer NDP for with `proxy_neighbours`.
+    #[serde(default)]
+    pub v6_proxy: Vec<std::net::Ipv6Addr>,
+    pub dhcp: Option<Dhcp>,
+    /// Free-form data for the templates.
+    pub extra: Option<serde_json::Value>,
 }
 
-#[derive(serde::Deserialize)]
+/// DHCP settings of a VPS, anything left out falls back to the global ones
+/// in the template.
+#[derive(serde::Deserialize, serde::Serialize, Default)]
+pub struct Dhcp {
+    /// MAC address of the VPS's own interface, to reserve its address for.
+    pub hw_address: Option<MacAddress>,
+    pub hostname: Option<String>,
+    #[serde(default)]
+    pub dns_servers: Vec<std::net::Ipv4Addr>,
+    #[serde(default)]
+    pub domain_search: Vec<String>,
+    #[serde(default)]
+    pub ntp_servers: Vec<std::net::Ipv4Addr>,
+    /// Lease times in seconds.
+    pub valid_lifetime: Option<u32>,
+    pub renew_timer: Option<u32>,
+    pub rebind_timer: Option<u32>,
+}
+
+impl Dhcp {
+    fn validate(&self, name: &str) -> Result<(), String> {
+        // Names end up in the DHCP server config as they are.
+        let valid_name = |n: &String| !n.is_empty() && n.len() <= 253 &&
+            n.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.');
+        if let Some(hostname) = self.hostname.as_ref().filter(|h| !valid_name(h)) {
+            return Err(format!("DHCP hostname {} of {} is not a valid host name", hostname, name));
+        }
+        if let Some(domain) = self.domain_search.iter().find(|d| !valid_name(d)) {
+            return Err(format!("DHCP search domain {} of {} is not a valid domain name", domain, name));
+        }
+        if self.hw_address.is_some_and(|mac| mac.0[0] & 1 != 0 || mac.0 == [0; 6]) {
+            return Err(format!("DHCP hardware address of {} is not a unicast address", name));
+        }
+        Ok(())
+    }
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+/// What happens to traffic towards the public addresses of a VPS. Either way
+/// other than active its interface and address stay as they are.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum VPSState {
+    #[default]
+    Active,
+    /// Routed to unreachable and left out of DHCP and router advertisements.
+    Suspended,
+    /// Routed to a blackhole, DHCP and router advertisements carry on.
+    NullRouted,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl std::fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let m = &self.0;
+        write!(f, "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", m[0], m[1], m[2], m[3], m[4], m[5])
+    }
+}
+
+impl std::str::FromStr for MacAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut mac = [0; 6];
+        let mut parts = s.split([':', '-']);
+        for octet in mac.iter_mut() {
+            *octet = parts.next()
+                .filter(|p| p.len() == 2 && p.chars().all(|c| c.is_ascii_hexdigit()))
+                .and_then(|p| u8::from_str_radix(p, 16).ok())
+                .ok_or_else(|| format!("invalid MAC address {}", s))?;
+        }
+        if parts.next().is_some() {
+            return Err(format!("invalid MAC address {}", s));
+        }
+        Ok(MacAddress(mac))
+    }
+}
+
+impl<'de> serde::de::Deserialize<'de> for MacAddress {
+    fn deserialize<D: serde::de::Deserializer<'de>>(de: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(de)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl serde::ser::Serialize for MacAddress {
+    fn serialize<S: serde::ser::Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+        ser.collect_str(self)
+    }
+}
+
+/// Attributes of the routes towards a VPS, anything left out keeps the kernel default.
+#[derive(serde::Deserialize, serde::Serialize, Default, Clone)]
+pub struct RouteAttributes {
+    /// Routes of a VPS in a VRF always go in the VRF's table.
+    pub table: Option<u32>,
+    pub metric: Option<u32>,
+    pub v4_pref_src: Option<std::net::Ipv4Addr>,
+    pub v6_pref_src: Option<std::net::Ipv6Addr>,
+    pub mtu: Option<u32>,
+    pub scope: Option<u8>,
+}
+
+impl RouteAttributes {
+    /// Fill in everything not set here from `defaults`.
+    fn or(&self, defaults: &RouteAttributes) -> RouteAttributes {
+        RouteAttributes {
+            table: self.table.or(defaults.table),
+            metric: self.metric.or(defaults.metric),
+            v4_pref_src: self.v4_pref_src.or(defaults.v4_pref_src),
+            v6_pref_src: self.v6_pref_src.or(defaults.v6_pref_src),
+            mtu: self.mtu.or(defaults.mtu),
+            scope: self.scope.or(defaults.scope),
+        }
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct Vxlan {
+    pub vni: u32,
+    pub local: std::net::IpAddr,
+    #[serde(default = "default_vxlan_port")]
+    pub port: u16,
+    pub remote: Option<std::net::IpAddr>,
+    pub group: Option<std::net::IpAddr>,
+    /// Interface the tunnel sends from, which the kernel needs for a group.
+    pub dev: Option<String>,
+}
+
+fn default_vxlan_port() -> u16 {
+    4789
+}
+
+/// How a VPS is attached to the network.
+pub enum Link<'a> {
+    Vlan(u16),
+    Vxlan(&'a Vxlan),
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
 #[serde(untagged)]
+pub enum SysctlValue {
+    Number(i64),
+    String(String),
+}
+
+impl std::fmt::Display for SysctlValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Number(n) => write!(f, "{}", n),
+            Self::String(s) => f.write_str(s),
+        }
+    }
+}
+
+/// Split a sysctl key like `ipv6.accept_ra` into its address family and name.
+pub fn split_sysctl(key: &str) -> Option<(&str, &str)> {
+    let (family, name) = key.split_once('.')?;
+    let valid_name = !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    Some((family, name)).filter(|_| matches!(family, "ipv4" | "ipv6") && valid_name)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv4Prefix {
+    pub address: std::net::Ipv4Addr,
+    pub length: u8,
+}
+
+impl std::fmt::Display for Ipv4Prefix {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.address, self.length)
+    }
+}
+
+impl std::str::FromStr for Ipv4Prefix {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, length) = s.split_once('/')
+            .ok_or_else(|| format!("invalid prefix {}", s))?;
+        let prefix = Ipv4Prefix {
+            address: address.parse().map_err(|_| format!("invalid prefix {}", s))?,
+            length: length.parse().ok()
+                .filter(|l| *l <= 32)
+                .ok_or_else(|| format!("invalid prefix {}", s))?,
+        };
+        if prefix.network() != prefix.address {
+            return Err(format!("prefix {} has host bits set", s));
+        }
+        Ok(prefix)
+    }
+}
+
+impl<'de> serde::de::Deserialize<'de> for Ipv4Prefix {
+    fn deserialize<D: serde::de::Deserializer<'de>>(de: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(de)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl Ipv4Prefix {
+    fn mask(&self) -> u32 {
+        u32::MAX.checked_shl(32 - self.length as u32).unwrap_or(0)
+    }
+
+    fn network(&self) -> std::net::Ipv4Addr {
+        std::net::Ipv4Addr::from(u32::from(self.address) & self.mask())
+    }
+
+    pub fn contains(&self, other: &Ipv4Prefix) -> bool {
+        other.length >= self.length && u32::from(other.address) & self.mask() == u32::from(self.address)
+    }
+
+    fn overlaps(&self, other: &Ipv4Prefix) -> bool {
+        self.contains(other) || other.contains(self)
+    }
+
+    /// What's left of this prefix with `holes` taken out of it, in as few
+    /// prefixes as it takes.
+    pub fn subtract(&self, holes: &[Ipv4Prefix]) -> Vec<Ipv4Prefix> {
+        if !holes.iter().any(|h| h.overlaps(self)) {
+            return vec![*self];
+        }
+        if holes.iter().any(|h| h.contains(self)) {
+            return vec![];
+        }
+        // Some hole is strictly inside, so this is shorter than a /32.
+        let length = self.length + 1;
+        let upper = Ipv4Prefix {
+            address: std::net::Ipv4Addr::from(u32::from(self.address) | 1 << (32 - length)),
+            length,
+        };
+        let lower = Ipv4Prefix {
+            address: self.address,
+            length,
+        };
+        let mut rest = lower.subtract(holes);
+        rest.extend(upper.subtract(holes));
+        rest
+    }
+}
+
+impl From<std::net::Ipv4Addr> for Ipv4Prefix {
+    fn from(address: std::net::Ipv4Addr) -> Self {
+        Ipv4Prefix {
+            address,
+            length: 32,
+        }
+    }
+}
+
 pub enum V4Ip {
     One(std::net::Ipv4Addr),
     Many(Vec<std::net::Ipv4Addr>),
@@ -29,6 +370,33 @@ impl V4Ip {
     }
 }
 
+/// Not untagged, so a bad address in a list is reported by its index rather
+/// than as matching neither variant.
+impl<'de> serde::de::Deserialize<'de> for V4Ip {
+    fn deserialize<D: serde::de::Deserializer<'de>>(de: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = V4Ip;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("an IPv4 address or a list of them")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<V4Ip, E> {
+                v.parse().map(V4Ip::One).map_err(E::custom)
+            }
+
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(self, seq: A) -> Result<V4Ip, A::Error> {
+                let de = serde::de::value::SeqAccessDeserializer::new(seq);
+                serde::de::Deserialize::deserialize(de).map(V4Ip::Many)
+            }
+        }
+
+        de.deserialize_any(Visitor)
+    }
+}
+
 impl serde::ser::Serialize for V4Ip {
     fn serialize<S: serde::ser::Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
         use serde::ser::SerializeSeq;
@@ -40,4 +408,808 @@ impl serde::ser::Serialize for V4Ip {
         }
         seq.end()
     }
-}
\ No newline at end of file
+}
+
+/// Prefix every managed interface name must carry, this is how
+/// interfaces owned by us are told apart from everything else on the host.
+pub const INTERFACE_PREFIX: &str = "vps";
+
+/// Linux limits interface names to IFNAMSIZ - 1 bytes.
+const MAX_INTERFACE_NAME_LEN: usize = 15;
+
+impl VPS {
+    /// The interface name for this VPS, either the one given in the config or
+    /// `vps{vlan}` (`vps{svlan}.{vlan}` for QinQ, `vpsx{vni}` for VXLAN), so
+    /// names stay the same across hosts and restarts.
+    pub fn interface_name(&self) -> String {
+        if let Some(name) = &self.name {
+            return name.clone();
+        }
+        match (self.link(), self.svlan) {
+            (Link::Vxlan(vxlan), _) => format!("{}x{}", INTERFACE_PREFIX, vxlan.vni),
+            (Link::Vlan(vlan), Some(svlan)) => format!("{}{}.{}", INTERFACE_PREFIX, svlan, vlan),
+            (Link::Vlan(vlan), None) => format!("{}{}", INTERFACE_PREFIX, vlan),
+        }
+    }
+
+    /// Only meaningful on a validated config, where exactly one of `vlan` and
+    /// `vxlan` is set.
+    pub fn link(&self) -> Link<'_> {
+        match (&self.vxlan, self.vlan) {
+            (Some(vxlan), _) => Link::Vxlan(vxlan),
+            (None, vlan) => Link::Vlan(vlan.unwrap_or_default()),
+        }
+    }
+
+    /// The routing table of the VRF the VPS is isolated in, falling back to
+    /// the global one.
+    pub fn vrf(&self, default: Option<u32>) -> Option<u32> {
+        self.vrf.or(default)
+    }
+
+    /// Route attributes for this VPS, falling back field by field to the
+    /// global ones.
+    pub fn route_attributes(&self, default: Option<&RouteAttributes>) -> RouteAttributes {
+        let default = default.cloned().unwrap_or_default();
+        match &self.route {
+            Some(route) => route.or(&default),
+            None => default,
+        }
+    }
+
+    /// The VPS's end of the /31, `v4_addr` being the host's.
+    pub fn customer_v4(&self) -> std::net::Ipv4Addr {
+        std::net::Ipv4Addr::from(u32::from(self.v4_addr) ^ 1)
+    }
+
+    /// The interface the VLAN sits on, falling back to the global one.
+    pub fn parent_interface<'a>(&'a self, default: &'a str) -> &'a str {
+        self.interface.as_deref().unwrap_or(default)
+    }
+
+    /// Sysctls for the interface, the VPS's own taking precedence over the
+    /// global ones.
+    pub fn sysctls(
+        &self, default: &std::collections::BTreeMap<String, SysctlValue>,
+    ) -> std::collections::BTreeMap<String, String> {
+        default.iter().chain(&self.sysctls)
+            .map(|(k, v)| (k.clone(), v.to_string()))
+            .collect()
+    }
+}
+
+/// Name of the 802.1ad interface carrying the S-tag, shared by every VPS in it.
+pub fn outer_interface_name(svlan: u16) -> String {
+    format!("{}q{}", INTERFACE_PREFIX, svlan)
+}
+
+/// Name of the VRF interface for a routing table, shared by every VPS in it.
+pub fn vrf_interface_name(table: u32) -> String {
+    format!("{}vrf{}", INTERFACE_PREFIX, table)
+}
+
+impl Vxlan {
+    fn validate(&self) -> Result<(), String> {
+        if self.vni > 0xffffff {
+            return Err(format!("VNI {} is out of range", self.vni));
+        }
+        let peer = match (self.remote, self.group) {
+            (Some(_), Some(_)) => {
+                return Err(format!("VNI {} can't have both a remote and a group", self.vni));
+            }
+            (Some(remote), None) if remote.is_multicast() => {
+                return Err(format!("VNI {} remote {} is a multicast address, use group", self.vni, remote));
+            }
+            (None, Some(group)) if !group.is_multicast() => {
+                return Err(format!("VNI {} group {} is not a multicast address", self.vni, group));
+            }
+            (None, Some(_)) if self.dev.is_none() => {
+                return Err(format!("VNI {} has a group but no dev to send it from", self.vni));
+            }
+            (remote, group) => remote.or(group),
+        };
+        if let Some(peer) = peer {
+            if peer.is_ipv4() != self.local.is_ipv4() {
+                return Err(format!("VNI {} local and remote addresses are of different families", self.vni));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Formats the config can be written in, all for the same schema.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Yaml,
+    Toml,
+}
+
+impl Format {
+    /// The format going by the extension of a file, if it's a known one.
+    pub fn from_path(path: &std::path::Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "json" => Some(Self::Json),
+            "yaml" | "yml" => Some(Self::Yaml),
+            "toml" => Some(Self::Toml),
+            _ => None,
+        }
+    }
+
+    pub fn parse<T: serde::de::DeserializeOwned>(self, data: &[u8]) -> Result<T, String> {
+        match self {
+            Self::Json => serde_json::from_slice(data).map_err(|e| e.to_string()),
+            Self::Yaml => {
+                // Merge keys are how YAML shares defaults through anchors, but
+                // serde_yaml only resolves them when asked to.
+                let mut value: serde_yaml::Value = serde_yaml::from_slice(data).map_err(|e| e.to_string())?;
+                value.apply_merge().map_err(|e| e.to_string())?;
+                serde_yaml::from_value(value).map_err(|e| e.to_string())
+            }
+            Self::Toml => {
+                let data = std::str::from_utf8(data).map_err(|e| e.to_string())?;
+                toml::from_str(data).map_err(|e| e.to_string())
+            }
+        }
+    }
+
+    /// Write a config in this format, as `migrate-config` does.
+    pub fn serialize(self, value: &serde_json::Value) -> Result<String, String> {
+        match self {
+            Self::Json => serde_json::to_string_pretty(value)
+                .map(|s| s + "\n")
+                .map_err(|e| e.to_string()),
+            Self::Yaml => serde_yaml::to_string(value).map_err(|e| e.to_string()),
+            Self::Toml => toml::to_string(value).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+/// Version of the config as written now. Older configs are upgraded in
+/// memory when loaded, and rewritten by `migrate-config`.
+pub const VERSION: u64 = 1;
+
+/// The migration at index n upgrades a config from version n to n + 1.
+const MIGRATIONS: [fn(&mut serde_json::Map<String, serde_json::Value>); VERSION as usize] = [
+    migrate_v0,
+];
+
+/// Version 1 only adds `version` itself, whatever else a version 0 config
+/// has is still read the same.
+fn migrate_v0(_config: &mut serde_json::Map<String, serde_json::Value>) {}
+
+/// Version of a config as parsed from its file, 0 if it has none.
+pub fn version(config: &serde_json::Value) -> Result<u64, String> {
+    let version = match config.get("version") {
+        None => 0,
+        Some(v) => v.as_u64().ok_or_else(|| format!("invalid version {}", v))?,
+    };
+    if version > VERSION {
+        return Err(format!("version {} is newer than the supported version {}", version, VERSION));
+    }
+    Ok(version)
+}
+
+/// Upgrade the main config to `VERSION`.
+pub fn migrate(config: &mut serde_json::Value) -> Result<(), String> {
+    let version = version(config)?;
+    let serde_json::Value::Object(map) = config else {
+        return Err("expected a map".to_string());
+    };
+    for migration in &MIGRATIONS[version as usize..] {
+        migration(map);
+    }
+    // Put the version first when adding it, it's the first thing to look for
+    // in a rewritten file.
+    let mut migrated = serde_json::Map::new();
+    migrated.insert("version".to_string(), VERSION.into());
+    migrated.extend(std::mem::take(map).into_iter().filter(|(k, _)| k != "version"));
+    *map = migrated;
+    Ok(())
+}
+
+/// Drop every `null`, which TOML has no way to write. They're the same as
+/// leaving the field out anyway.
+pub fn strip_nulls(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            map.retain(|_, v| !v.is_null());
+            map.values_mut().for_each(strip_nulls);
+        }
+        serde_json::Value::Array(values) => {
+            values.retain(|v| !v.is_null());
+            values.iter_mut().for_each(strip_nulls);
+        }
+        _ => {}
+    }
+}
+
+/// Upgrade a file of the config directory, which is of the version of the
+/// main config, by migrating it as part of a config of that version.
+pub fn migrate_file(file: &mut serde_json::Value, version: u64) -> Result<(), String> {
+    let serde_json::Value::Object(map) = file else {
+        return Err("expected a VPS or a partial config".to_string());
+    };
+    let single_vps = is_single_vps(map)?;
+    let mut config = if !single_vps {
+        std::mem::take(map)
+    } else {
+        let vps = serde_json::Value::Object(std::mem::take(map));
+        serde_json::Map::from_iter([("vps".to_string(), serde_json::Value::Array(vec![vps]))])
+    };
+    for migration in &MIGRATIONS[version as usize..] {
+        migration(&mut config);
+    }
+    *file = if single_vps {
+        config["vps"][0].take()
+    } else {
+        serde_json::Value::Object(config)
+    };
+    Ok(())
+}
+
+/// Whether a file of the config directory is one VPS, told apart from a
+/// partial config by `v4_addr`, which every VPS has and a config doesn't.
+fn is_single_vps(file: &serde_json::Map<String, serde_json::Value>) -> Result<bool, String> {
+    match (file.contains_key("v4_addr"), file.contains_key("vps")) {
+        (true, true) => Err("can't be both a VPS (v4_addr) and a partial config (vps)".to_string()),
+        (single_vps, _) => Ok(single_vps),
+    }
+}
+
+/// Add a file from the config directory to the main config. A file with a
+/// `v4_addr` is one VPS. Any other file is a partial config, such as one only
+/// setting `template_vars`, whose lists are appended and maps merged, while
+/// anything else may only be set once.
+pub fn merge_file(config: &mut serde_json::Value, file: serde_json::Value) -> Result<(), String> {
+    let serde_json::Value::Object(config) = config else {
+        return Err("the main config isn't a map".to_string());
+    };
+    let serde_json::Value::Object(file) = file else {
+        return Err("expected a VPS or a partial config".to_string());
+    };
+    if !is_single_vps(&file)? {
+        merge_map("", config, file)
+    } else {
+        match config.entry("vps").or_insert_with(|| serde_json::Value::Array(vec![])) {
+            serde_json::Value::Array(vps) => vps.push(serde_json::Value::Object(file)),
+            _ => return Err("vps in the main config isn't a list".to_string()),
+        }
+        Ok(())
+    }
+}
+
+fn merge_map(
+    path: &str,
+    into: &mut serde_json::Map<String, serde_json::Value>,
+    from: serde_json::Map<String, serde_json::Value>,
+) -> Result<(), String> {
+    for (key, value) in from {
+        let key_path = if path.is_empty() { key.clone() } else { format!("{}.{}", path, key) };
+        match (into.get_mut(&key), value) {
+            (None, value) => {
+                into.insert(key, value);
+            }
+            (Some(serde_json::Value::Array(into)), serde_json::Value::Array(from)) => into.extend(from),
+            (Some(serde_json::Value::Object(into)), serde_json::Value::Object(from)) => merge_map(&key_path, into, from)?,
+            (Some(existing), value) if *existing == value => {}
+            (Some(_), _) => return Err(format!("{} is already set", key_path)),
+        }
+    }
+    Ok(())
+}
+
+/// A path as `serde_path_to_error` writes them.
+fn ignored_path(path: &serde_ignored::Path) -> String {
+    match path {
+        serde_ignored::Path::Root => String::new(),
+        serde_ignored::Path::Seq { parent, index } => format!("{}[{}]", ignored_path(parent), index),
+        serde_ignored::Path::Map { parent, key } => match ignored_path(parent) {
+            parent if parent.is_empty() => key.clone(),
+            parent => format!("{}.{}", parent, key),
+        },
+        serde_ignored::Path::Some { parent }
+        | serde_ignored::Path::NewtypeStruct { parent }
+        | serde_ignored::Path::NewtypeVariant { parent } => ignored_path(parent),
+    }
+}
+
+impl Config {
+    /// Merge the main config and the files of the config directory, in that
+    /// order, and validate the result. Errors name the file that caused them,
+    /// which for clashes between files is the later one. Unknown fields are
+    /// errors too, unless allowed, when they're only warned about.
+    pub fn from_files(
+        files: &[(std::path::PathBuf, serde_json::Value)],
+        allow_unknown_fields: bool,
+    ) -> Result<Self, String> {
+        let merge = |files: &[(std::path::PathBuf, serde_json::Value)]| -> Result<serde_json::Value, String> {
+            let mut config = files[0].1.clone();
+            for (path, file) in &files[1..] {
+                merge_file(&mut config, file.clone()).map_err(|e| format!("{}: {}", path.display(), e))?;
+            }
+            Ok(config)
+        };
+        let build = |config: serde_json::Value| -> Result<(Self, Vec<String>), String> {
+            let (config, unknown_fields) = Self::deserialize(config)?;
+            if !allow_unknown_fields && !unknown_fields.is_empty() {
+                return Err(format!("unknown field {}", unknown_fields.join(", ")));
+            }
+            config.validate()?;
+            Ok((config, unknown_fields))
+        };
+
+        let err = match build(merge(files)?) {
+            Ok((config, unknown_fields)) => {
+                for field in unknown_fields {
+                    warn!("Ignoring unknown config field {}", field);
+                }
+                return Ok(config);
+            }
+            Err(err) => err,
+        };
+        // Find the file the error first shows up with by adding them one by
+        // one, which is quadratic, so only done on failure. Fewer files can
+        // fail differently, e.g. when a later one sets a required field.
+        let n = (1..files.len())
+            .find(|n| merge(&files[..*n]).map(build).is_ok_and(|r| r.is_err_and(|e| e == err)))
+            .unwrap_or(files.len());
+        Err(format!("{}: {}", files[n - 1].0.display(), err))
+    }
+
+    /// Deserialize a config along with the paths of the fields it doesn't
+    /// know, with errors located by their path, as in `vps[12].v4_public[1]`.
+    /// Top-level fields starting with `x-` are left alone, as a place for YAML
+    /// anchors, anywhere else they're as unknown as any other.
+    fn deserialize(config: serde_json::Value) -> Result<(Self, Vec<String>), String> {
+        let mut unknown_fields = vec![];
+        let mut callback = |path: serde_ignored::Path| {
+            let anchors = matches!(
+                &path,
+                serde_ignored::Path::Map { parent: serde_ignored::Path::Root, key } if key.starts_with("x-")
+            );
+            if !anchors {
+                unknown_fields.push(ignored_path(&path));
+            }
+        };
+        let deserializer = serde_ignored::Deserializer::new(config, &mut callback);
+        let config = serde_path_to_error::deserialize(deserializer).map_err(|e| {
+            if e.path().iter().next().is_none() {
+                e.inner().to_string()
+            } else {
+                format!("{}: {}", e.path(), e.inner())
+            }
+        })?;
+        Ok((config, unknown_fields))
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.version != VERSION {
+            return Err(format!("version {} isn't the current version {}, migrate the config first", self.version, VERSION));
+        }
+
+        for output in &self.outputs {
+            if output.reload_command.is_some() && output.signal_daemon.is_some() {
+                return Err(format!("output {} can't have both a reload command and a daemon to signal", output.output.display()));
+            }
+            if output.reload_command.as_ref().is_some_and(|c| c.is_empty()) {
+                return Err(format!("reload command of output {} is empty", output.output.display()));
+            }
+        }
+
+        let mut names = std::collections::HashSet::new();
+        let mut vlans = std::collections::HashSet::new();
+        let mut svlans = std::collections::HashMap::new();
+        let mut vnis = std::collections::HashSet::new();
+        let mut vrfs = std::collections::HashSet::new();
+        for vps in &self.vps {
+            if let Some(table) = vps.vrf(self.vrf) {
+                // 0 is unspecified, 253 to 255 are the default, main and local tables.
+                if table == 0 || (253..=255).contains(&table) {
+                    return Err(format!("routing table {} is reserved and can't be used for a VRF", table));
+                }
+                let name = vrf_interface_name(table);
+                if name.len() > MAX_INTERFACE_NAME_LEN {
+                    return Err(format!("routing table {} is too large for a VRF", table));
+                }
+                vrfs.insert(name);
+
+                if let Some(route_table) = vps.route.as_ref().and_then(|r| r.table) {
+                    if route_table != table {
+                        return Err(format!("routing table {} differs from VRF table {}", route_table, table));
+                    }
+                }
+            }
+
+            match (vps.vlan, &vps.vxlan) {
+                (Some(_), Some(_)) | (None, None) => {
+                    return Err("exactly one of vlan and vxlan must be set for each VPS".to_string());
+                }
+                (None, Some(vxlan)) => {
+                    vxlan.validate()?;
+                    if !vnis.insert(vxlan.vni) {
+                        return Err(format!("VNI {} used more than once", vxlan.vni));
+                    }
+                    if vps.svlan.is_some() {
+                        return Err(format!("VNI {} can't have an S-VLAN", vxlan.vni));
+                    }
+                    if vps.interface.is_some() {
+                        return Err(format!("VNI {} can't have a parent interface, use dev", vxlan.vni));
+                    }
+                    continue;
+                }
+                (Some(_), None) => {}
+            }
+
+            let parent = vps.parent_interface(&self.interface);
+            if !vlans.insert((parent, vps.svlan, vps.vlan)) {
+                return Err(format!("VLAN {} used more than once on {}", vps.vlan.unwrap_or_default(), parent));
+            }
+            if let Some(svlan) = vps.svlan {
+                if *svlans.entry(svlan).or_insert(parent) != parent {
+                    return Err(format!("S-VLAN {} used on more than one parent interface", svlan));
+                }
+            }
+        }
+
+        if let Some(key) = self.sysctls.keys()
+            .chain(self.vps.iter().flat_map(|vps| vps.sysctls.keys()))
+            .find(|k| split_sysctl(k).is_none()) {
+            return Err(format!("invalid sysctl {}, expected ipv4.<name> or ipv6.<name>", key));
+        }
+
+        for (i, prefix) in self.owned_prefixes.iter().enumerate() {
+            if let Some(other) = self.owned_prefixes[..i].iter().find(|o| o.overlaps(prefix)) {
+                return Err(format!("owned prefixes {} and {} overlap", other, prefix));
+            }
+        }
+
+        let mut reserved_names = svlans.keys().map(|s| outer_interface_name(*s)).collect::<Vec<_>>();
+        reserved_names.extend(vrfs);
+        for vps in &self.vps {
+            let name = vps.interface_name();
+            if vps.mtu.is_some_and(|mtu| mtu < 1280) {
+                return Err(format!("MTU of {} is below the IPv6 minimum of 1280", name));
+            }
+            if vps.mac.is_some_and(|mac| mac.0[0] & 1 != 0 || mac.0 == [0; 6]) {
+                return Err(format!("MAC address of {} is not a unicast address", name));
+            }
+            if let Some(addr) = vps.v6_proxy.iter().find(|a| u128::from(**a) >> 64 != u128::from(vps.v6_prefix) >> 64) {
+                return Err(format!("proxied address {} of {} is outside its prefix", addr, name));
+            }
+            if let Some(dhcp) = &vps.dhcp {
+                dhcp.validate(&name)?;
+            }
+            // The kernel counts rates in bytes.
+            if vps.ingress_rate.is_some_and(|r| r < 8) || vps.egress_rate.is_some_and(|r| r < 8) {
+                return Err(format!("rate limit of {} is below 8 bit/s", name));
+            }
+            if !name.starts_with(INTERFACE_PREFIX) {
+                return Err(format!("interface name {} must start with {}", name, INTERFACE_PREFIX));
+            }
+            if name.len() > MAX_INTERFACE_NAME_LEN {
+                return Err(format!("interface name {} is longer than {} characters", name, MAX_INTERFACE_NAME_LEN));
+            }
+            // The kernel's rules, the name also ends up in sysctl paths.
+            if name == "." || name == ".." || name.contains(|c: char| c == '/' || c == ':' || c.is_whitespace()) {
+                return Err(format!("interface name {:?} isn't valid", name));
+            }
+            if reserved_names.contains(&name) {
+                return Err(format!("interface name {} is used for an S-VLAN or VRF interface", name));
+            }
+            if !names.insert(name.clone()) {
+                return Err(format!("interface name {} used more than once, set a name for each VPS sharing a VLAN", name));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mac_address_from_str() {
+        let mac = MacAddress([0x02, 0x00, 0x5e, 0x10, 0xab, 0xff]);
+        assert_eq!("02:00:5e:10:ab:ff".parse::<MacAddress>().unwrap().0, mac.0);
+        assert_eq!("02-00-5E-10-AB-FF".parse::<MacAddress>().unwrap().0, mac.0);
+        assert_eq!(mac.to_string(), "02:00:5e:10:ab:ff");
+    }
+
+    #[test]
+    fn mac_address_from_str_invalid() {
+        for s in ["", "02:00:5e:10:ab", "02:00:5e:10:ab:ff:00", "2:00:5e:10:ab:ff", "02:00:5e:10:ab:fg", "+2:00:5e:10:ab:ff", "0200.5e10.abff"] {
+            assert!(s.parse::<MacAddress>().is_err(), "{}", s);
+        }
+    }
+
+    fn prefixes(prefixes: &[&str]) -> Vec<Ipv4Prefix> {
+        prefixes.iter().map(|p| p.parse().unwrap()).collect()
+    }
+
+    fn subtract(prefix: &str, holes: &[&str]) -> Vec<String> {
+        prefix.parse::<Ipv4Prefix>().unwrap()
+            .subtract(&prefixes(holes))
+            .iter()
+            .map(|p| p.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn ipv4_prefix_from_str() {
+        let prefix = "192.0.2.0/24".parse::<Ipv4Prefix>().unwrap();
+        assert_eq!(prefix.address, std::net::Ipv4Addr::new(192, 0, 2, 0));
+        assert_eq!(prefix.length, 24);
+        assert_eq!("0.0.0.0/0".parse::<Ipv4Prefix>().unwrap().length, 0);
+        for s in ["192.0.2.0", "192.0.2.0/33", "192.0.2.0/-1", "192.0.2/24", "192.0.2.1/24"] {
+            assert!(s.parse::<Ipv4Prefix>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn ipv4_prefix_subtract_no_holes() {
+        assert_eq!(subtract("192.0.2.0/24", &[]), ["192.0.2.0/24"]);
+    }
+
+    #[test]
+    fn ipv4_prefix_subtract_whole() {
+        assert!(subtract("192.0.2.0/24", &["192.0.2.0/24"]).is_empty());
+        assert!(subtract("192.0.2.0/24", &["192.0.0.0/16"]).is_empty());
+    }
+
+    #[test]
+    fn ipv4_prefix_subtract_outside() {
+        assert_eq!(subtract("192.0.2.0/24", &["198.51.100.1/32", "192.0.3.0/24"]), ["192.0.2.0/24"]);
+    }
+
+    #[test]
+    fn ipv4_prefix_subtract_single() {
+        assert_eq!(subtract("192.0.2.0/30", &["192.0.2.1/32"]), ["192.0.2.0/32", "192.0.2.2/31"]);
+    }
+
+    #[test]
+    fn ipv4_prefix_subtract_nested() {
+        // The /32 inside the /30 hole changes nothing.
+        assert_eq!(
+            subtract("192.0.2.0/28", &["192.0.2.4/30", "192.0.2.5/32", "192.0.2.8/32"]),
+            ["192.0.2.0/30", "192.0.2.9/32", "192.0.2.10/31", "192.0.2.12/30"],
+        );
+    }
+
+    #[test]
+    fn ipv4_prefix_subtract_overlapping() {
+        assert_eq!(
+            subtract("192.0.2.0/29", &["192.0.2.0/30", "192.0.2.2/31", "192.0.2.7/32"]),
+            ["192.0.2.4/31", "192.0.2.6/32"],
+        );
+    }
+
+    fn vps(vlan: u16, v4_addr: &str) -> serde_json::Value {
+        serde_json::json!({
+            "vlan": vlan,
+            "v4_addr": v4_addr,
+            "v6_prefix": format!("2001:db8:{}::", vlan),
+        })
+    }
+
+    fn config() -> serde_json::Value {
+        serde_json::json!({
+            "version": VERSION,
+            "rt_proto": 200,
+            "interface": "eth0",
+            "template_vars": {"resolvers": ["192.0.2.53"]},
+        })
+    }
+
+    #[test]
+    fn merge_map_appends_and_merges() {
+        let serde_json::Value::Object(mut into) = serde_json::json!({
+            "interface": "eth0",
+            "owned_prefixes": ["192.0.2.0/24"],
+            "template_vars": {"a": 1, "nested": {"b": 2}},
+        }) else { unreachable!() };
+        let serde_json::Value::Object(from) = serde_json::json!({
+            "interface": "eth0",
+            "owned_prefixes": ["198.51.100.0/24"],
+            "template_vars": {"c": 3, "nested": {"d": 4}},
+        }) else { unreachable!() };
+        merge_map("", &mut into, from).unwrap();
+        assert_eq!(serde_json::Value::Object(into), serde_json::json!({
+            "interface": "eth0",
+            "owned_prefixes": ["192.0.2.0/24", "198.51.100.0/24"],
+            "template_vars": {"a": 1, "nested": {"b": 2, "d": 4}, "c": 3},
+        }));
+    }
+
+    #[test]
+    fn merge_map_conflict() {
+        let serde_json::Value::Object(mut into) = serde_json::json!({"template_vars": {"nested": {"a": 1}}}) else { unreachable!() };
+        let serde_json::Value::Object(from) = serde_json::json!({"template_vars": {"nested": {"a": 2}}}) else { unreachable!() };
+        assert_eq!(merge_map("", &mut into, from).unwrap_err(), "template_vars.nested.a is already set");
+        let serde_json::Value::Object(from) = serde_json::json!({"template_vars": []}) else { unreachable!() };
+        assert_eq!(merge_map("", &mut into, from).unwrap_err(), "template_vars is already set");
+    }
+
+    #[test]
+    fn merge_file_kinds() {
+        let mut merged = config();
+        merge_file(&mut merged, vps(1001, "100.64.0.0")).unwrap();
+        merge_file(&mut merged, serde_json::json!({"vps": [vps(1002, "100.64.0.2")]})).unwrap();
+        merge_file(&mut merged, serde_json::json!({"template_vars": {"domain": "example.com"}})).unwrap();
+        assert_eq!(merged["vps"], serde_json::json!([vps(1001, "100.64.0.0"), vps(1002, "100.64.0.2")]));
+        assert_eq!(merged["template_vars"], serde_json::json!({"resolvers": ["192.0.2.53"], "domain": "example.com"}));
+
+        let mut both = vps(1003, "100.64.0.4");
+        both["vps"] = serde_json::json!([]);
+        assert!(merge_file(&mut merged, both).is_err());
+        assert!(merge_file(&mut merged, serde_json::json!([])).is_err());
+    }
+
+    #[test]
+    fn from_files_merged() {
+        let files = [
+            (std::path::PathBuf::from("config.json"), config()),
+            (std::path::PathBuf::from("d/1.json"), vps(1001, "100.64.0.0")),
+            (std::path::PathBuf::from("d/2.json"), serde_json::json!({"template_vars": {"domain": "example.com"}})),
+        ];
+        let config = Config::from_files(&files, false).unwrap();
+        assert_eq!(config.vps.len(), 1);
+        assert_eq!(config.template_vars.len(), 2);
+    }
+
+    #[test]
+    fn from_files_names_culprit() {
+        let mut unknown = vps(1002, "100.64.0.2");
+        unknown["v6_prefx"] = unknown["v6_prefix"].clone();
+        let files = [
+            (std::path::PathBuf::from("config.json"), config()),
+            (std::path::PathBuf::from("d/1.json"), vps(1001, "100.64.0.0")),
+            (std::path::PathBuf::from("d/2.json"), unknown),
+            (std::path::PathBuf::from("d/3.json"), vps(1003, "100.64.0.4")),
+        ];
+        let err = Config::from_files(&files, false).err().unwrap();
+        assert_eq!(err, "d/2.json: unknown field vps[1].v6_prefx");
+        assert!(Config::from_files(&files, true).is_ok());
+    }
+
+    #[test]
+    fn from_files_reports_full_error() {
+        let mut config = config();
+        config.as_object_mut().unwrap().remove("rt_proto");
+        let files = [
+            (std::path::PathBuf::from("m.json"), config),
+            (std::path::PathBuf::from("d/00-globals.json"), serde_json::json!({"rt_proto": 200})),
+            (std::path::PathBuf::from("d/a.json"), vps(1001, "100.64.0.0")),
+            (std::path::PathBuf::from("d/b.json"), vps(1001, "100.64.0.2")),
+        ];
+        // Fewer files fail as well, but for the missing rt_proto.
+        let err = Config::from_files(&files, false).err().unwrap();
+        assert_eq!(err, "d/b.json: VLAN 1001 used more than once on eth0");
+    }
+
+    #[test]
+    fn migrate_adds_version_first() {
+        let mut config = serde_json::json!({"rt_proto": 200, "interface": "eth0"});
+        assert_eq!(version(&config).unwrap(), 0);
+        migrate(&mut config).unwrap();
+        assert_eq!(version(&config).unwrap(), VERSION);
+        assert_eq!(config.as_object().unwrap().keys().collect::<Vec<_>>(), ["version", "rt_proto", "interface"]);
+
+        // Already current, nothing changes.
+        let original = config.clone();
+        migrate(&mut config).unwrap();
+        assert_eq!(config, original);
+    }
+
+    #[test]
+    fn migrate_invalid_version() {
+        for version in [serde_json::json!(VERSION + 1), serde_json::json!("1"), serde_json::json!(-1)] {
+            let mut config = serde_json::json!({"version": version});
+            assert!(migrate(&mut config).is_err(), "{}", config);
+        }
+        assert!(migrate(&mut serde_json::json!([])).is_err());
+    }
+
+    #[test]
+    fn migrate_file_keeps_kind() {
+        let mut single = vps(1001, "100.64.0.0");
+        let original = single.clone();
+        migrate_file(&mut single, 0).unwrap();
+        assert_eq!(single, original);
+
+        let mut partial = serde_json::json!({"template_vars": {"domain": "example.com"}});
+        let original = partial.clone();
+        migrate_file(&mut partial, 0).unwrap();
+        assert_eq!(partial, original);
+
+        assert!(migrate_file(&mut serde_json::json!("vps"), 0).is_err());
+    }
+
+    #[test]
+    fn strip_nulls_for_toml() {
+        let mut config = serde_json::json!({
+            "version": VERSION,
+            "vrf": null,
+            "vps": [{"v4_addr": "100.64.0.0", "v4_public": null, "extra": {"a": null, "b": [1, null]}}],
+        });
+        assert!(Format::Toml.serialize(&config).is_err());
+        strip_nulls(&mut config);
+        assert_eq!(config, serde_json::json!({
+            "version": VERSION,
+            "vps": [{"v4_addr": "100.64.0.0", "extra": {"b": [1]}}],
+        }));
+        let toml = Format::Toml.serialize(&config).unwrap();
+        assert_eq!(Format::Toml.parse::<serde_json::Value>(toml.as_bytes()).unwrap(), config);
+    }
+
+    fn unknown_fields(config: serde_json::Value) -> Vec<String> {
+        Config::deserialize(config).unwrap().1
+    }
+
+    #[test]
+    fn deserialize_unknown_fields() {
+        let mut config = config();
+        config["x-defaults"] = serde_json::json!({"mtu": 1500});
+        config["routes"] = serde_json::json!([]);
+        let mut vps = vps(1001, "100.64.0.0");
+        vps["x-v6_prefix"] = serde_json::json!("2001:db8::");
+        vps["dhcp"] = serde_json::json!({"hostname": "vps1001", "hostnme": "vps1001"});
+        config["vps"] = serde_json::json!([vps]);
+        assert_eq!(unknown_fields(config), ["routes", "vps[0].x-v6_prefix", "vps[0].dhcp.hostnme"]);
+    }
+
+    #[test]
+    fn deserialize_v4_public() {
+        let mut one = vps(1001, "100.64.0.0");
+        one["v4_public"] = serde_json::json!("192.0.2.1");
+        let mut many = vps(1002, "100.64.0.2");
+        many["v4_public"] = serde_json::json!(["192.0.2.2", "192.0.2.3"]);
+        let mut config = config();
+        config["vps"] = serde_json::json!([one, many]);
+        let (config, _) = Config::deserialize(config).unwrap();
+        let v4_public = config.vps.iter()
+            .map(|vps| vps.v4_public.as_ref().unwrap().as_many().to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(v4_public, [
+            vec![std::net::Ipv4Addr::new(192, 0, 2, 1)],
+            vec![std::net::Ipv4Addr::new(192, 0, 2, 2), std::net::Ipv4Addr::new(192, 0, 2, 3)],
+        ]);
+    }
+
+    #[test]
+    fn deserialize_v4_public_invalid() {
+        for (v4_public, err) in [
+            (serde_json::json!(["192.0.2.2", "192.0.2.300"]), "vps[0].v4_public[1]: "),
+            (serde_json::json!("192.0.2"), "vps[0].v4_public: "),
+            (serde_json::json!(1), "vps[0].v4_public: "),
+        ] {
+            let mut vps = vps(1001, "100.64.0.0");
+            vps["v4_public"] = v4_public;
+            let mut config = config();
+            config["vps"] = serde_json::json!([vps]);
+            let result = Config::deserialize(config).err().unwrap();
+            assert!(result.starts_with(err), "{}", result);
+        }
+    }
+
+    #[test]
+    fn validate_interface_names() {
+        for (name, valid) in [
+            ("vps-web.1", true),
+            ("vps/1", false),
+            ("vps:1", false),
+            ("vps 1", false),
+            ("vps\t1", false),
+            ("web1", false),
+            ("vps-0123456789ab", false),
+        ] {
+            let mut vps = vps(1001, "100.64.0.0");
+            vps["name"] = serde_json::json!(name);
+            let mut config = config();
+            config["vps"] = serde_json::json!([vps]);
+            let files = [(std::path::PathBuf::from("config.json"), config)];
+            assert_eq!(Config::from_files(&files, false).is_ok(), valid, "{}", name);
+        }
+    }
+}
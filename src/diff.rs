@@ -1,4 +1,65 @@
-use crate::{Error, netlink, config};
+use crate::{Error, netlink, config, sysctl};
+
+#[derive(Debug)]
+pub enum InterfaceKind {
+    Vlan {
+        link_name: String,
+        vlan: u16,
+        protocol: u16,
+    },
+    Vxlan(netlink::Vxlan),
+}
+
+#[derive(Debug)]
+pub struct AddInterface {
+    name: String,
+    kind: InterfaceKind,
+}
+
+#[derive(Debug)]
+pub struct AddVrf {
+    name: String,
+    table: u32,
+}
+
+#[derive(Debug)]
+pub struct SetMaster {
+    interface_name: String,
+    master_name: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct ModifyInterface {
+    interface_name: String,
+    mtu: Option<u32>,
+    mac: Option<config::MacAddress>,
+    up: Option<bool>,
+}
+
+impl ModifyInterface {
+    fn is_empty(&self) -> bool {
+        self.mtu.is_none() && self.mac.is_none() && self.up.is_none()
+    }
+}
+
+#[derive(Debug)]
+pub struct SetRateLimit {
+    interface_name: String,
+    /// In bits per second, `None` lifts the limit.
+    rate: Option<u64>,
+    /// Whether the interface has a qdisc for the limit already, for a new
+    /// egress limit only if it's one of ours to change in place.
+    existing: bool,
+}
+
+impl std::fmt::Display for SetRateLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.rate {
+            Some(rate) => write!(f, "on {} to {} bit/s", self.interface_name, rate),
+            None => write!(f, "on {}", self.interface_name),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct AddAddress {
@@ -11,50 +72,607 @@ pub struct AddAddress {
 pub struct AddRoute {
     destination: std::net::IpAddr,
     destination_prefix_length: u8,
+    /// Only set for unicast routes, blackhole and unreachable ones go nowhere.
+    interface_name: Option<String>,
+    kind: u8,
+    table: u32,
+    metric: Option<u32>,
+    pref_src: Option<std::net::IpAddr>,
+    mtu: Option<u32>,
+    scope: Option<u8>,
+}
+
+/// Metric the kernel gives IPv6 routes added without one.
+const IP6_RT_PRIO_USER: u32 = 1024;
+
+impl std::fmt::Display for AddRoute {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.interface_name {
+            Some(interface_name) => write!(
+                f, "route {}/{} via {}", self.destination, self.destination_prefix_length, interface_name
+            )?,
+            None => write!(
+                f, "{} route {}/{}", route_kind_name(self.kind), self.destination, self.destination_prefix_length
+            )?,
+        }
+        write!(f, " in table {}", self.table)
+    }
+}
+
+fn route_kind_name(kind: u8) -> &'static str {
+    match kind {
+        netlink_packet_route::constants::RTN_UNICAST => "unicast",
+        netlink_packet_route::constants::RTN_BLACKHOLE => "blackhole",
+        netlink_packet_route::constants::RTN_UNREACHABLE => "unreachable",
+        _ => "other",
+    }
+}
+
+impl AddRoute {
+    /// Whether an existing route is this one, down to its attributes. Only
+    /// unicast routes have to be on the given interface.
+    fn fits(&self, route: &netlink::Route, interface: Option<u32>) -> bool {
+        let default_metric = match self.destination {
+            std::net::IpAddr::V4(_) => 0,
+            std::net::IpAddr::V6(_) => IP6_RT_PRIO_USER,
+        };
+        // IPv6 routes have no scope, the kernel always reports them as universe.
+        let scope_matches = match self.destination {
+            std::net::IpAddr::V4(_) => {
+                route.scope == self.scope.unwrap_or(netlink_packet_route::constants::RT_SCOPE_UNIVERSE)
+            }
+            std::net::IpAddr::V6(_) => true,
+        };
+
+        let interface_matches = self.kind != netlink_packet_route::constants::RTN_UNICAST ||
+            Some(route.interface) == interface;
+
+        route.destination == self.destination &&
+            route.destination_prefix_length == self.destination_prefix_length &&
+            route.kind == self.kind &&
+            interface_matches &&
+            route.table == self.table &&
+            route.metric == self.metric.unwrap_or(default_metric) &&
+            route.pref_src == self.pref_src &&
+            route.mtu == self.mtu &&
+            scope_matches
+    }
+
+    /// Whether an existing route has the same key in the kernel's routing table,
+    /// so that it can be replaced by this one in a single step.
+    fn replaces(&self, route: &netlink::Route) -> bool {
+        let default_metric = match self.destination {
+            std::net::IpAddr::V4(_) => 0,
+            std::net::IpAddr::V6(_) => IP6_RT_PRIO_USER,
+        };
+
+        route.destination == self.destination &&
+            route.destination_prefix_length == self.destination_prefix_length &&
+            route.table == self.table &&
+            route.metric == self.metric.unwrap_or(default_metric)
+    }
+}
+
+/// The routes a VPS should have, out of its interface while it's active and
+/// dropped otherwise. A route has the same table and metric either way, so
+/// suspending a VPS replaces its routes in place.
+fn vps_routes(
+    vps: &config::VPS, interface_name: &str, table: u32, attributes: &config::RouteAttributes,
+) -> Vec<AddRoute> {
+    let mut routes = vec![];
+
+    let route = |destination: std::net::IpAddr, destination_prefix_length: u8, pref_src: Option<std::net::IpAddr>| {
+        let kind = match vps.state {
+            config::VPSState::Active => netlink_packet_route::constants::RTN_UNICAST,
+            config::VPSState::Suspended => netlink_packet_route::constants::RTN_UNREACHABLE,
+            config::VPSState::NullRouted => netlink_packet_route::constants::RTN_BLACKHOLE,
+        };
+        if kind == netlink_packet_route::constants::RTN_UNICAST {
+            AddRoute {
+                destination,
+                destination_prefix_length,
+                interface_name: Some(interface_name.to_string()),
+                kind,
+                table,
+                metric: attributes.metric,
+                pref_src,
+                mtu: attributes.mtu,
+                scope: attributes.scope,
+            }
+        } else {
+            AddRoute {
+                destination,
+                destination_prefix_length,
+                interface_name: None,
+                kind,
+                table,
+                metric: attributes.metric,
+                pref_src: None,
+                mtu: None,
+                scope: None,
+            }
+        }
+    };
+
+    if let Some(public_v4) = &vps.v4_public {
+        for addr in public_v4.as_many() {
+            routes.push(route(
+                std::net::IpAddr::V4(*addr), 32, attributes.v4_pref_src.map(std::net::IpAddr::V4),
+            ));
+        }
+    }
+
+    routes.push(route(
+        std::net::IpAddr::V6(vps.v6_prefix), 64, attributes.v6_pref_src.map(std::net::IpAddr::V6),
+    ));
+
+    routes
+}
+
+#[derive(Debug)]
+pub struct AddProxy {
+    address: std::net::IpAddr,
     interface_name: String,
 }
 
+/// A sysctl of an interface, looked up by the interface's name only when
+/// it's set, as a rename planned before may not have happened.
+#[derive(Debug)]
+pub struct SetSysctl {
+    interface_name: String,
+    family: String,
+    name: String,
+    value: String,
+}
+
+/// What the uplink needs to answer for addresses routed elsewhere: proxy ARP
+/// only happens on a forwarding interface, proxy NDP has its own switch too.
+/// Forwarding turns off router advertisements unless `accept_ra` is 2, which
+/// would take the host's own IPv6 default route with it.
+const PROXY_SYSCTLS: [(&str, &str, &str); 4] = [
+    ("ipv4", "forwarding", "1"),
+    ("ipv6", "accept_ra", "2"),
+    ("ipv6", "forwarding", "1"),
+    ("ipv6", "proxy_ndp", "1"),
+];
+
+#[derive(Debug)]
+pub struct RenameInterface {
+    index: u32,
+    /// The name at the time of the rename, for the plan log.
+    from: String,
+    name: String,
+}
+
 #[derive(Debug)]
 pub enum Diff {
-    AddInterface(netlink::Interface),
+    AddInterface(AddInterface),
     RemoveInterface(u32),
+    RenameInterface(RenameInterface),
+    AddVrf(AddVrf),
+    SetMaster(SetMaster),
+    ModifyInterface(ModifyInterface),
+    SetEgressLimit(SetRateLimit),
+    SetIngressLimit(SetRateLimit),
     AddAddress(AddAddress),
-    RemoveAddress(netlink_packet_route::address::AddressMessage),
+    RemoveAddress(netlink::Address),
     AddRoute(AddRoute),
-    RemoveRoute(netlink_packet_route::route::RouteMessage),
+    ModifyRoute(AddRoute),
+    RemoveRoute(netlink::Route),
+    AddUnassignedRoute(AddRoute),
+    RemoveUnassignedRoute(netlink::Route),
+    AddProxy(AddProxy),
+    RemoveProxy(netlink::Proxy),
+    SetSysctl(SetSysctl),
+}
+
+impl std::fmt::Display for Diff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::AddInterface(i) => write!(f, "add interface {}", i.name),
+            Self::RemoveInterface(i) => write!(f, "remove interface {}", i),
+            Self::RenameInterface(r) => write!(f, "rename interface {} to {}", r.from, r.name),
+            Self::AddVrf(v) => write!(f, "add VRF {} for table {}", v.name, v.table),
+            Self::SetMaster(m) => match &m.master_name {
+                Some(master) => write!(f, "enslave {} to {}", m.interface_name, master),
+                None => write!(f, "release {} from its master", m.interface_name),
+            },
+            Self::ModifyInterface(m) => {
+                write!(f, "modify interface {}", m.interface_name)?;
+                if let Some(mtu) = m.mtu {
+                    write!(f, ", MTU {}", mtu)?;
+                }
+                if let Some(mac) = m.mac {
+                    write!(f, ", MAC {}", mac)?;
+                }
+                match m.up {
+                    Some(true) => write!(f, ", up"),
+                    Some(false) => write!(f, ", down"),
+                    None => Ok(()),
+                }
+            }
+            Self::SetEgressLimit(l) if l.rate.is_none() => write!(f, "remove egress limit {}", l),
+            Self::SetEgressLimit(l) => write!(f, "limit egress {}", l),
+            Self::SetIngressLimit(l) if l.rate.is_none() => write!(f, "remove ingress limit {}", l),
+            Self::SetIngressLimit(l) => write!(f, "limit ingress {}", l),
+            Self::AddAddress(a) => write!(f, "add address {}/{} on {}", a.address, a.prefix_length, a.interface_name),
+            Self::RemoveAddress(a) => write!(f, "remove address {}/{} from interface {}", a.address, a.prefix_length, a.interface),
+            Self::AddRoute(r) => write!(f, "add {}", r),
+            Self::ModifyRoute(r) => write!(f, "modify {}", r),
+            Self::RemoveRoute(r) => write!(
+                f, "remove {} route {}/{} via interface {} in table {}",
+                route_kind_name(r.kind), r.destination, r.destination_prefix_length, r.interface, r.table
+            ),
+            Self::AddUnassignedRoute(r) => write!(f, "add unassigned {}", r),
+            Self::RemoveUnassignedRoute(r) => write!(
+                f, "remove unassigned {} route {}/{} in table {}",
+                route_kind_name(r.kind), r.destination, r.destination_prefix_length, r.table
+            ),
+            Self::AddProxy(p) => write!(f, "add proxy neighbour {} on {}", p.address, p.interface_name),
+            Self::RemoveProxy(p) => write!(f, "remove proxy neighbour {} from interface {}", p.address, p.interface),
+            Self::SetSysctl(s) => write!(
+                f, "set {} to {}", sysctl::interface_path(&s.family, &s.interface_name, &s.name).display(), s.value
+            ),
+        }
+    }
 }
 
 #[derive(serde::Serialize)]
 pub struct InterfaceState<'a> {
     pub name: String,
     pub vps: &'a config::VPS,
+    /// The DHCP options of the VPS by their name in Kea, with lists already
+    /// joined up.
+    pub dhcp_options: Vec<DhcpOption>,
+    /// Our end of the /31 and the VPS's end.
+    pub v4_addr: std::net::Ipv4Addr,
+    pub customer_v4: std::net::Ipv4Addr,
+    pub v4_cidr: String,
+    pub v6_cidr: String,
+    /// Public addresses of the VPS, empty rather than missing if there are none.
+    pub v4_public: Vec<std::net::Ipv4Addr>,
+    pub v4_public_cidrs: Vec<String>,
+    /// Only known once the interface exists, see `set_link`.
+    pub index: Option<u32>,
+    pub mac: Option<config::MacAddress>,
+    pub link_local: Option<std::net::Ipv6Addr>,
+}
+
+#[derive(serde::Serialize)]
+pub struct DhcpOption {
+    pub name: &'static str,
+    pub data: String,
+}
+
+impl<'a> InterfaceState<'a> {
+    pub fn new(name: String, vps: &'a config::VPS) -> Self {
+        let mut dhcp_options = vec![];
+        if let Some(dhcp) = &vps.dhcp {
+            let mut option = |name, data: Vec<String>| {
+                if !data.is_empty() {
+                    dhcp_options.push(DhcpOption {
+                        name,
+                        data: data.join(", "),
+                    });
+                }
+            };
+            option("domain-name-servers", dhcp.dns_servers.iter().map(|a| a.to_string()).collect());
+            option("domain-search", dhcp.domain_search.clone());
+            option("ntp-servers", dhcp.ntp_servers.iter().map(|a| a.to_string()).collect());
+            option("host-name", dhcp.hostname.iter().cloned().collect());
+        }
+
+        let v4_public = vps.v4_public.as_ref().map(|v4| v4.as_many().to_vec()).unwrap_or_default();
+        let v6_prefix = std::net::Ipv6Addr::from(u128::from(vps.v6_prefix) & !(u128::MAX >> 64));
+
+        InterfaceState {
+            name,
+            vps,
+            dhcp_options,
+            v4_addr: vps.v4_addr,
+            customer_v4: vps.customer_v4(),
+            v4_cidr: format!("{}/31", vps.v4_addr),
+            v6_cidr: format!("{}/64", v6_prefix),
+            v4_public_cidrs: v4_public.iter().map(|a| format!("{}/32", a)).collect(),
+            v4_public,
+            index: None,
+            mac: None,
+            link_local: None,
+        }
+    }
+
+    /// Fill in what the interface looks like now that it exists.
+    pub fn set_link(&mut self, link: &netlink::LinkDetails) {
+        self.index = Some(link.index);
+        self.mac = link.mac.map(config::MacAddress);
+        self.link_local = link.link_local;
+    }
+}
+
+/// What an existing interface has to look like to be kept.
+#[derive(Debug)]
+enum Link {
+    Vlan {
+        link: u32,
+        vlan: u16,
+        protocol: u16,
+    },
+    Vxlan(netlink::Vxlan),
+}
+
+impl Link {
+    fn fits(&self, i: &netlink::Interface) -> bool {
+        match self {
+            Self::Vlan { link, vlan, protocol } => {
+                i.vxlan.is_none() && i.vlan == *vlan && i.link == *link && i.protocol == *protocol
+            }
+            Self::Vxlan(vxlan) => i.vxlan.as_ref() == Some(vxlan),
+        }
+    }
+}
+
+/// Find the existing interface that best fits a VPS, preferring one that already
+/// has the right VLAN and parent over one that merely has the right name. VLAN
+/// ids are only unique per parent, so a VLAN on another parent never matches.
+fn find_interface<'s>(
+    state: &'s netlink::State, claimed: &[u32], name: &str, link: &Link,
+) -> Option<&'s netlink::Interface> {
+    state.interfaces.iter()
+        .filter(|i| !claimed.contains(&i.index))
+        .filter(|i| i.name == name || link.fits(i))
+        .max_by_key(|i| (link.fits(i), i.name == name))
+}
+
+fn vxlan_link(vxlan: &config::Vxlan, link: Option<u32>) -> netlink::Vxlan {
+    netlink::Vxlan {
+        vni: vxlan.vni,
+        local: Some(vxlan.local),
+        port: vxlan.port,
+        remote: vxlan.remote.or(vxlan.group),
+        link,
+    }
 }
 
 pub async fn make_diff<'a>(
-    handle: &rtnetlink::Handle, root_interface: &str, target: &'a [config::VPS],
-    state: netlink::State,
+    handle: &rtnetlink::Handle, config: &'a config::Config, state: netlink::State,
 ) -> Result<(Vec<Diff>, Vec<InterfaceState<'a>>), Error> {
     let mut keep_interfaces = vec![];
     let mut keep_routes = vec![];
     let mut rem_addresses = vec![];
 
+    let mut renames = vec![];
+
     let mut diff_add = vec![];
     let mut diff = vec![];
     let mut interface_states = vec![];
 
-    let mut next_interface_id = state.interfaces.iter().map(|i| {
-        usize::from_str_radix(&i.name[3..], 10).unwrap_or(0)
-    }).max().unwrap_or(0) + 1;
-    let link_interface = netlink::interface_name_to_index(handle, root_interface).await?;
+    let mut claimed_interfaces = vec![];
+
+    let mut link_interfaces = std::collections::HashMap::new();
+    let mut outer_interfaces = std::collections::HashMap::new();
+    let mut vrf_interfaces = std::collections::HashMap::new();
+    let mut keep_vrfs = vec![];
+    let mut wanted_names = vec![];
+    let mut new_vnis = vec![];
+    let mut wanted_routes = vec![];
+    let mut diff_outer = vec![];
+
+    for vps in &config.vps {
+        let interface_name = vps.interface_name();
+        wanted_names.push(interface_name.clone());
+        let (link, kind) = match vps.link() {
+            config::Link::Vxlan(vxlan) => {
+                let dev = match &vxlan.dev {
+                    Some(dev) => Some(match link_interfaces.get(dev.as_str()) {
+                        Some(i) => *i,
+                        None => {
+                            let i = netlink::interface_name_to_index(handle, dev).await?;
+                            link_interfaces.insert(dev.as_str(), i);
+                            i
+                        }
+                    }),
+                    None => None,
+                };
+                let vxlan = vxlan_link(vxlan, dev);
+                (Link::Vxlan(vxlan.clone()), InterfaceKind::Vxlan(vxlan))
+            }
+            config::Link::Vlan(vlan) => {
+                let parent = vps.parent_interface(&config.interface);
+                let parent_interface = match link_interfaces.get(parent) {
+                    Some(i) => *i,
+                    None => {
+                        let i = netlink::interface_name_to_index(handle, parent).await?;
+                        link_interfaces.insert(parent, i);
+                        i
+                    }
+                };
+
+                // With QinQ the VLAN sits on the 802.1ad interface for its S-tag, which is
+                // set up the first time a VPS in it comes along. The index of an outer
+                // interface yet to be created is 0, so nothing existing matches it.
+                let (link_name, link_interface) = match vps.svlan {
+                    None => (parent.to_string(), parent_interface),
+                    Some(svlan) => {
+                        let outer_name = config::outer_interface_name(svlan);
+                        wanted_names.push(outer_name.clone());
+                        let outer_index = match outer_interfaces.get(&svlan) {
+                            Some(i) => *i,
+                            None => {
+                                let outer_link = Link::Vlan {
+                                    link: parent_interface,
+                                    vlan: svlan,
+                                    protocol: netlink::ETH_P_8021AD,
+                                };
+                                let index = match find_interface(&state, &claimed_interfaces, &outer_name, &outer_link) {
+                                    Some(i) if outer_link.fits(i) => {
+                                        claimed_interfaces.push(i.index);
+                                        keep_interfaces.push(i.index);
+                                        if i.name != outer_name {
+                                            renames.push((i, outer_name.clone()));
+                                        }
+                                        i.index
+                                    }
+                                    e => {
+                                        if let Some(i) = e {
+                                            warn!("Interface {} is not S-VLAN {} on {}, recreating", i.name, svlan, parent);
+                                            claimed_interfaces.push(i.index);
+                                        }
+                                        diff_outer.push(Diff::AddInterface(AddInterface {
+                                            name: outer_name.clone(),
+                                            kind: InterfaceKind::Vlan {
+                                                link_name: parent.to_string(),
+                                                vlan: svlan,
+                                                protocol: netlink::ETH_P_8021AD,
+                                            },
+                                        }));
+                                        0
+                                    }
+                                };
+                                outer_interfaces.insert(svlan, index);
+                                index
+                            }
+                        };
+                        (outer_name, outer_index)
+                    }
+                };
+
+                (Link::Vlan {
+                    link: link_interface,
+                    vlan,
+                    protocol: netlink::ETH_P_8021Q,
+                }, InterfaceKind::Vlan {
+                    link_name,
+                    vlan,
+                    protocol: netlink::ETH_P_8021Q,
+                })
+            }
+        };
+
+        // Like outer interfaces, VRFs are shared and created on first use.
+        let vrf = vps.vrf(config.vrf);
+        let master = match vrf {
+            None => None,
+            Some(table) => {
+                let vrf_name = config::vrf_interface_name(table);
+                wanted_names.push(vrf_name.clone());
+                let vrf_index = match vrf_interfaces.get(&table) {
+                    Some(i) => *i,
+                    None => {
+                        let index = match state.vrfs.iter().find(|v| v.name == vrf_name) {
+                            Some(v) if v.table == table => {
+                                keep_vrfs.push(v.index);
+                                v.index
+                            }
+                            v => {
+                                if let Some(v) = v {
+                                    warn!("VRF {} uses table {}, expected {}, recreating", v.name, v.table, table);
+                                }
+                                diff_outer.push(Diff::AddVrf(AddVrf {
+                                    name: vrf_name.clone(),
+                                    table,
+                                }));
+                                0
+                            }
+                        };
+                        vrf_interfaces.insert(table, index);
+                        index
+                    }
+                };
+                Some((vrf_name, vrf_index))
+            }
+        };
+        let route_attributes = vps.route_attributes(config.route.as_ref());
+        let table = vrf.or(route_attributes.table)
+            .unwrap_or(netlink_packet_route::constants::RT_TABLE_MAIN as u32);
+
+        let existing = match find_interface(&state, &claimed_interfaces, &interface_name, &link) {
+            Some(i) if link.fits(i) => Some(i),
+            Some(i) => {
+                // The VLAN id, parent or VXLAN settings of a link can't be changed in place, so
+                // leave it out of keep_interfaces to have it removed and build
+                // it again from scratch.
+                warn!("Interface {} does not match {:?}, recreating", i.name, link);
+                claimed_interfaces.push(i.index);
+                None
+            }
+            None => None,
+        };
 
-    for vps in target {
-        match state.interfaces.iter().find(|i| i.vlan == vps.vlan) {
+        match existing {
             Some(i) => {
+                claimed_interfaces.push(i.index);
                 keep_interfaces.push(i.index);
-                interface_states.push(InterfaceState {
-                    name: i.name.clone(),
-                    vps,
-                });
+                interface_states.push(InterfaceState::new(interface_name.clone(), vps));
+
+                if i.name != interface_name {
+                    renames.push((i, interface_name.clone()));
+                }
+
+                // Enslaving cycles the interface and flushes its routes, so this
+                // goes ahead of adding any of them.
+                let master_changed = match &master {
+                    Some((_, index)) => i.master != *index || *index == 0,
+                    None => i.master != 0,
+                };
+                if master_changed {
+                    diff_add.push(Diff::SetMaster(SetMaster {
+                        interface_name: interface_name.clone(),
+                        master_name: master.as_ref().map(|(name, _)| name.clone()),
+                    }));
+                }
+
+                let modify = ModifyInterface {
+                    interface_name: interface_name.clone(),
+                    mtu: vps.mtu.filter(|mtu| *mtu != i.mtu),
+                    mac: vps.mac.filter(|mac| Some(mac.0) != i.mac),
+                    up: Some(vps.enabled).filter(|up| *up != i.up),
+                };
+                if !modify.is_empty() {
+                    diff_add.push(Diff::ModifyInterface(modify));
+                }
+
+                // Rates are compared in bytes, which is what the kernel keeps.
+                if vps.egress_rate.map(|r| r / 8) != i.egress_rate {
+                    diff_add.push(Diff::SetEgressLimit(SetRateLimit {
+                        interface_name: interface_name.clone(),
+                        rate: vps.egress_rate,
+                        // A TBF put there by anything else, e.g. with tc's
+                        // automatic handle, can only be replaced, though it's
+                        // removed all the same.
+                        existing: match vps.egress_rate {
+                            Some(_) => i.egress_handle == Some(netlink::TBF_HANDLE),
+                            None => i.egress_handle.is_some(),
+                        },
+                    }));
+                }
+                if vps.ingress_rate.map(|r| r / 8) != i.ingress_rate {
+                    diff_add.push(Diff::SetIngressLimit(SetRateLimit {
+                        interface_name: interface_name.clone(),
+                        rate: vps.ingress_rate,
+                        existing: i.ingress_qdisc,
+                    }));
+                }
+
+                // Sysctls are checked on every update, so anything else changing
+                // them gets put right.
+                for (key, value) in vps.sysctls(&config.sysctls) {
+                    let Some((family, name)) = config::split_sysctl(&key) else {
+                        continue;
+                    };
+                    let current = sysctl::read(&sysctl::interface_path(family, &i.name, name)).await?;
+                    if current.as_deref() != Some(value.as_str()) {
+                        warn!(
+                            "Sysctl {} of {} is {}, expected {}",
+                            key, interface_name, current.as_deref().unwrap_or("missing"), value
+                        );
+                        diff_add.push(Diff::SetSysctl(SetSysctl {
+                            interface_name: interface_name.clone(),
+                            family: family.to_string(),
+                            name: name.to_string(),
+                            value,
+                        }));
+                    }
+                }
 
                 let mut found_v4_addr = false;
 
@@ -64,7 +682,7 @@ pub async fn make_diff<'a>(
                             if &vps.v4_addr == dest && address.prefix_length == 31 {
                                 found_v4_addr = true;
                             } else {
-                                rem_addresses.push(address.message.clone());
+                                rem_addresses.push(address.clone());
                             }
                         }
                         std::net::IpAddr::V6(_) => {}
@@ -75,160 +693,770 @@ pub async fn make_diff<'a>(
                     diff_add.push(Diff::AddAddress(AddAddress {
                         address: std::net::IpAddr::V4(vps.v4_addr),
                         prefix_length: 31,
-                        interface_name: i.name.clone(),
+                        interface_name: interface_name.clone(),
                     }));
                 }
 
-                let mut found_v4 = vec![];
-                let mut found_v6 = false;
-
-                for route in state.routes.iter().filter(|r| r.interface == i.index) {
-                    match route.destination {
-                        std::net::IpAddr::V4(dest) => {
-                            if let Some(public_v4) = &vps.v4_public {
-                                let addrs = public_v4.as_many();
-                                if addrs.contains(&dest) && route.destination_prefix_length == 32 {
-                                    keep_routes.push(route.message.clone());
-                                    found_v4.push(dest);
-                                }
-                            }
-                        }
-                        std::net::IpAddr::V6(dest) => {
-                            if vps.v6_prefix == dest && route.destination_prefix_length == 64 {
-                                keep_routes.push(route.message.clone());
-                                found_v6 = true;
-                            }
-                        }
-                    }
-                }
-
-                if let Some(public_v4) = &vps.v4_public {
-                    for addr in public_v4.as_many().iter().filter(|p| !found_v4.contains(p)) {
-                        diff_add.push(Diff::AddRoute(AddRoute {
-                            destination: std::net::IpAddr::V4(*addr),
-                            destination_prefix_length: 32,
-                            interface_name: i.name.clone(),
-                        }));
-                    }
-                }
-
-                if !found_v6 {
-                    diff_add.push(Diff::AddRoute(AddRoute {
-                        destination: std::net::IpAddr::V6(vps.v6_prefix),
-                        destination_prefix_length: 64,
-                        interface_name: i.name.clone(),
-                    }));
+                if vps.enabled || vps.state != config::VPSState::Active {
+                    wanted_routes.extend(
+                        vps_routes(vps, &interface_name, table, &route_attributes).into_iter().map(|r| (Some(i.index), r))
+                    );
                 }
             },
             None => {
-                let id = next_interface_id;
-                next_interface_id += 1;
-                let interface_name = format!("vps{}", id);
-
-                interface_states.push(InterfaceState {
-                    name: interface_name.clone(),
-                    vps,
-                });
+                interface_states.push(InterfaceState::new(interface_name.clone(), vps));
 
-                diff_add.push(Diff::AddInterface(netlink::Interface {
+                if let InterfaceKind::Vxlan(vxlan) = &kind {
+                    new_vnis.push(vxlan.vni);
+                }
+                diff_add.push(Diff::AddInterface(AddInterface {
                     name: interface_name.clone(),
-                    index: 0,
-                    link: link_interface,
-                    vlan: vps.vlan
+                    kind,
                 }));
-                diff_add.push(Diff::AddAddress(AddAddress {
-                    address: std::net::IpAddr::V4(vps.v4_addr),
-                    prefix_length: 31,
+                if let Some((master_name, _)) = master {
+                    diff_add.push(Diff::SetMaster(SetMaster {
+                        interface_name: interface_name.clone(),
+                        master_name: Some(master_name),
+                    }));
+                }
+                // New interfaces start out up.
+                let modify = ModifyInterface {
                     interface_name: interface_name.clone(),
-                }));
-                if let Some(public_v4) = &vps.v4_public {
-                    for addr in public_v4.as_many() {
-                        diff_add.push(Diff::AddRoute(AddRoute {
-                            destination: std::net::IpAddr::V4(*addr),
-                            destination_prefix_length: 32,
+                    mtu: vps.mtu,
+                    mac: vps.mac,
+                    up: Some(false).filter(|_| !vps.enabled),
+                };
+                if !modify.is_empty() {
+                    diff_add.push(Diff::ModifyInterface(modify));
+                }
+                if vps.egress_rate.is_some() {
+                    diff_add.push(Diff::SetEgressLimit(SetRateLimit {
+                        interface_name: interface_name.clone(),
+                        rate: vps.egress_rate,
+                        existing: false,
+                    }));
+                }
+                if vps.ingress_rate.is_some() {
+                    diff_add.push(Diff::SetIngressLimit(SetRateLimit {
+                        interface_name: interface_name.clone(),
+                        rate: vps.ingress_rate,
+                        existing: false,
+                    }));
+                }
+                for (key, value) in vps.sysctls(&config.sysctls) {
+                    if let Some((family, name)) = config::split_sysctl(&key) {
+                        diff_add.push(Diff::SetSysctl(SetSysctl {
                             interface_name: interface_name.clone(),
+                            family: family.to_string(),
+                            name: name.to_string(),
+                            value,
                         }));
                     }
                 }
-                diff_add.push(Diff::AddRoute(AddRoute {
-                    destination: std::net::IpAddr::V6(vps.v6_prefix.clone()),
-                    destination_prefix_length: 64,
+                diff_add.push(Diff::AddAddress(AddAddress {
+                    address: std::net::IpAddr::V4(vps.v4_addr),
+                    prefix_length: 31,
                     interface_name: interface_name.clone(),
                 }));
+                if vps.enabled || vps.state != config::VPSState::Active {
+                    wanted_routes.extend(
+                        vps_routes(vps, &interface_name, table, &route_attributes).into_iter().map(|r| (None, r))
+                    );
+                }
+            }
+        }
+    }
+
+    // Routes already right are left alone. Of the others, one that only differs
+    // from an existing route in its interface or in attributes outside the
+    // kernel's lookup key (destination, table and metric) is replaced in place,
+    // so traffic to it isn't dropped in between.
+    let mut unmatched_routes = vec![];
+    for (index, route) in wanted_routes {
+        let existing = state.routes.iter().find(|r| {
+            !keep_routes.contains(&r.message) && route.fits(r, index)
+        });
+        match existing {
+            Some(r) => keep_routes.push(r.message.clone()),
+            None => unmatched_routes.push(route),
+        }
+    }
+    let mut diff_routes = vec![];
+    for route in unmatched_routes {
+        match state.routes.iter().find(|r| !keep_routes.contains(&r.message) && route.replaces(r)) {
+            Some(r) => {
+                keep_routes.push(r.message.clone());
+                diff_routes.push(Diff::ModifyRoute(route));
+            }
+            None => diff_routes.push(Diff::AddRoute(route)),
+        }
+    }
+
+    // Only addresses with a route of their own count as assigned, so those of
+    // a disabled VPS are dropped along with the rest.
+    let routed = config.vps.iter()
+        .filter(|vps| vps.enabled || vps.state != config::VPSState::Active)
+        .filter_map(|vps| vps.v4_public.as_ref().map(|v4| (vps.vrf(config.vrf), v4)))
+        .collect::<Vec<_>>();
+    let unassigned_kind = match config.unassigned_route {
+        config::UnassignedRoute::Blackhole => netlink_packet_route::constants::RTN_BLACKHOLE,
+        config::UnassignedRoute::Unreachable => netlink_packet_route::constants::RTN_UNREACHABLE,
+    };
+    let unassigned_attributes = config.route.clone().unwrap_or_default();
+    let unassigned_table = config.vrf.or(unassigned_attributes.table)
+        .unwrap_or(netlink_packet_route::constants::RT_TABLE_MAIN as u32);
+    // A VPS in a VRF of its own only ever looks up that VRF's table, so the
+    // unassigned space is dropped there as well. Within such a table only the
+    // addresses of the VPSes in the same VRF count as assigned.
+    let mut unassigned_tables = vec![(unassigned_table, None)];
+    for (vrf, _) in &routed {
+        if let Some(table) = vrf {
+            if *table != unassigned_table && !unassigned_tables.iter().any(|(t, _)| t == table) {
+                unassigned_tables.push((*table, Some(*table)));
+            }
+        }
+    }
+    for (table, vrf) in unassigned_tables {
+        let assigned = routed.iter()
+            .filter(|(v, _)| vrf.is_none() || *v == vrf)
+            .flat_map(|(_, v4)| v4.as_many().iter().map(|a| config::Ipv4Prefix::from(*a)))
+            .collect::<Vec<_>>();
+        for prefix in config.owned_prefixes.iter().flat_map(|p| p.subtract(&assigned)) {
+            let route = AddRoute {
+                destination: std::net::IpAddr::V4(prefix.address),
+                destination_prefix_length: prefix.length,
+                interface_name: None,
+                kind: unassigned_kind,
+                table,
+                metric: unassigned_attributes.metric,
+                pref_src: None,
+                mtu: None,
+                scope: None,
+            };
+            if let Some(r) = state.routes.iter().find(|r| !keep_routes.contains(&r.message) && route.fits(r, None)) {
+                keep_routes.push(r.message.clone());
+            } else if let Some(r) = state.routes.iter().find(|r| !keep_routes.contains(&r.message) && route.replaces(r)) {
+                keep_routes.push(r.message.clone());
+                diff_routes.push(Diff::ModifyRoute(route));
+            } else {
+                diff_routes.push(Diff::AddUnassignedRoute(route));
+            }
+        }
+    }
+
+    // Proxy neighbour entries are told apart by their protocol like routes, and
+    // only go up once the routes behind them are in place.
+    let mut diff_proxies = vec![];
+    let mut wanted_proxies = vec![];
+    let mut uplink = None;
+    if config.proxy_neighbours {
+        uplink = Some(netlink::interface_name_to_index(handle, &config.interface).await?);
+        for (family, name, value) in PROXY_SYSCTLS {
+            let path = sysctl::interface_path(family, &config.interface, name);
+            if sysctl::read(&path).await?.as_deref() != Some(value) {
+                diff_proxies.push(Diff::SetSysctl(SetSysctl {
+                    interface_name: config.interface.clone(),
+                    family: family.to_string(),
+                    name: name.to_string(),
+                    value: value.to_string(),
+                }));
+            }
+        }
+        for vps in &config.vps {
+            if let Some(public_v4) = &vps.v4_public {
+                wanted_proxies.extend(public_v4.as_many().iter().map(|a| std::net::IpAddr::V4(*a)));
             }
+            wanted_proxies.extend(vps.v6_proxy.iter().map(|a| std::net::IpAddr::V6(*a)));
+        }
+        wanted_proxies.sort();
+        wanted_proxies.dedup();
+    }
+    for address in &wanted_proxies {
+        if !state.proxies.iter().any(|p| Some(p.interface) == uplink && p.address == *address) {
+            diff_proxies.push(Diff::AddProxy(AddProxy {
+                address: *address,
+                interface_name: config.interface.clone(),
+            }));
         }
     }
 
+    // Interfaces are only removed once their routes have been moved elsewhere,
+    // out of the way under a temporary name if their name is needed. A VXLAN
+    // sharing its VNI with a new one has to go first though, as the kernel
+    // won't have both.
     let mut rem_interfaces = vec![];
+    let mut diff_remove = vec![];
 
-    for interface in &state.interfaces {
-        if !keep_interfaces.contains(&interface.index) {
+    // Removing an 802.1ad interface takes the VLANs on top of it along, so
+    // those have to go first.
+    let mut stale_interfaces = state.interfaces.iter()
+        .filter(|i| !keep_interfaces.contains(&i.index))
+        .collect::<Vec<_>>();
+    stale_interfaces.sort_by_key(|i| i.protocol == netlink::ETH_P_8021AD);
+
+    for interface in stale_interfaces {
+        if let Some(other) = state.interfaces.iter().find(|i| {
+            i.index != interface.index && i.vxlan.is_none() && interface.vxlan.is_none() &&
+                i.link == interface.link && i.vlan == interface.vlan && i.protocol == interface.protocol &&
+                keep_interfaces.contains(&i.index)
+        }) {
+            warn!("Interface {} duplicates VLAN {} of {}, removing", interface.name, interface.vlan, other.name);
+        }
+        if interface.vxlan.as_ref().is_some_and(|v| new_vnis.contains(&v.vni)) {
             diff.push(Diff::RemoveInterface(interface.index));
-            rem_interfaces.push(interface.index);
+        } else {
+            if wanted_names.contains(&interface.name) {
+                // Kernels before 6.2 can't rename an interface that's up, and
+                // this one is on its way out anyway.
+                if interface.up {
+                    diff.push(Diff::ModifyInterface(ModifyInterface {
+                        interface_name: interface.name.clone(),
+                        mtu: None,
+                        mac: None,
+                        up: Some(false),
+                    }));
+                }
+                diff.push(Diff::RenameInterface(RenameInterface {
+                    index: interface.index,
+                    from: interface.name.clone(),
+                    name: format!("{}r{}", config::INTERFACE_PREFIX, interface.index),
+                }));
+            }
+            diff_remove.push(Diff::RemoveInterface(interface.index));
         }
+        rem_interfaces.push(interface.index);
     }
 
+    // A VRF being recreated under the same name has to make way for the new one.
+    for vrf in state.vrfs.iter().filter(|v| !keep_vrfs.contains(&v.index)) {
+        if wanted_names.contains(&vrf.name) {
+            diff.push(Diff::RemoveInterface(vrf.index));
+        } else {
+            diff_remove.push(Diff::RemoveInterface(vrf.index));
+        }
+    }
+
+    // Unassigned space only stops being dropped once what replaces it is in place.
+    let vps_addresses = config.vps.iter()
+        .filter_map(|vps| vps.v4_public.as_ref())
+        .flat_map(|v4| v4.as_many().iter().copied())
+        .collect::<Vec<_>>();
     for route in &state.routes {
-        if !keep_routes.contains(&route.message) && !rem_interfaces.contains(&route.interface) {
-            diff.push(Diff::RemoveRoute(route.message.clone()));
+        if keep_routes.contains(&route.message) || rem_interfaces.contains(&route.interface) {
+            continue;
+        }
+        let unassigned = route.kind != netlink_packet_route::constants::RTN_UNICAST && match route.destination {
+            std::net::IpAddr::V4(v4) => route.destination_prefix_length != 32 || !vps_addresses.contains(&v4),
+            std::net::IpAddr::V6(_) => false,
+        };
+        if unassigned {
+            diff_remove.push(Diff::RemoveUnassignedRoute(route.clone()));
+        } else {
+            diff.push(Diff::RemoveRoute(route.clone()));
         }
     }
 
     for address in rem_addresses {
         diff.push(Diff::RemoveAddress(address));
     }
-    diff.extend(diff_add.into_iter());
+
+    for proxy in &state.proxies {
+        if Some(proxy.interface) != uplink || !wanted_proxies.contains(&proxy.address) {
+            diff.push(Diff::RemoveProxy(proxy.clone()));
+        }
+    }
+
+    // If one interface is to take over the current name of another, e.g. when
+    // two are swapped, move everything out of the way under a temporary name
+    // first.
+    let needs_temporary_names = renames.iter().any(|(i, name)| {
+        renames.iter().any(|(o, _)| o.index != i.index && &o.name == name)
+    });
+    let temporary_name = |i: &netlink::Interface| format!("{}r{}", config::INTERFACE_PREFIX, i.index);
+    if needs_temporary_names {
+        for (i, _) in &renames {
+            diff.push(Diff::RenameInterface(RenameInterface {
+                index: i.index,
+                from: i.name.clone(),
+                name: temporary_name(i),
+            }));
+        }
+    }
+    for (i, name) in renames {
+        diff.push(Diff::RenameInterface(RenameInterface {
+            index: i.index,
+            from: if needs_temporary_names { temporary_name(i) } else { i.name.clone() },
+            name,
+        }));
+    }
+
+    diff.extend(diff_outer);
+    diff.extend(diff_add);
+    diff.extend(diff_routes);
+    diff.extend(diff_proxies);
+    diff.extend(diff_remove);
 
     Ok((diff, interface_states))
 }
 
-pub async fn apply_diff(handle: &rtnetlink::Handle, route_proto: u8, diff: Vec<Diff>) -> Result<(), Error> {
+fn set_vlan_protocol(msg: &mut netlink_packet_route::LinkMessage, protocol: u16) {
+    for nla in &mut msg.nlas {
+        if let netlink_packet_route::nlas::link::Nla::Info(infos) = nla {
+            for info in infos {
+                if let netlink_packet_route::nlas::link::Info::Data(
+                    netlink_packet_route::nlas::link::InfoData::Vlan(data)
+                ) = info {
+                    // The attribute is big endian on the wire but emitted in native
+                    // byte order by netlink-packet-route, so swap it here.
+                    data.push(netlink_packet_route::nlas::link::InfoVlan::Protocol(
+                        u16::from_ne_bytes(protocol.to_be_bytes())
+                    ));
+                }
+            }
+        }
+    }
+}
+
+async fn set_egress_limit(handle: &rtnetlink::Handle, interface: u32, l: SetRateLimit) -> Result<(), Error> {
+    match l.rate {
+        // rtnetlink has no way to give a qdisc options, so the request is
+        // built by hand. Our TBF is changed in place, anything else at the
+        // root is replaced by it.
+        Some(rate) => {
+            let mut msg = netlink_packet_route::TcMessage::with_index(interface as i32);
+            msg.header.parent = netlink_packet_route::tc::constants::TC_H_ROOT;
+            msg.header.handle = netlink::TBF_HANDLE;
+            msg.nlas.push(netlink_packet_route::tc::Nla::Kind("tbf".to_string()));
+            msg.nlas.push(netlink_packet_route::tc::Nla::Options(netlink::tbf_options(rate / 8)));
+            let flags = if l.existing {
+                0
+            } else {
+                netlink_packet_core::NLM_F_CREATE | netlink_packet_core::NLM_F_REPLACE
+            };
+            netlink::send(handle, netlink_packet_route::RtnlMessage::NewQueueDiscipline(msg), flags).await?;
+        }
+        None if !l.existing => {}
+        None => {
+            let mut req = handle.qdisc().del(interface as i32);
+            req.message_mut().header.parent = netlink_packet_route::tc::constants::TC_H_ROOT;
+            req.execute().await?;
+        }
+    }
+    Ok(())
+}
+
+/// The policer sits in a filter matching everything on the ingress qdisc.
+/// Filters can't be replaced through rtnetlink, so the qdisc is recreated
+/// with the new one instead.
+async fn set_ingress_limit(handle: &rtnetlink::Handle, interface: u32, l: SetRateLimit) -> Result<(), Error> {
+    if l.existing {
+        let mut req = handle.qdisc().del(interface as i32);
+        req.message_mut().header.parent = netlink_packet_route::tc::constants::TC_H_INGRESS;
+        req.execute().await?;
+    }
+    if let Some(rate) = l.rate {
+        handle.qdisc().add(interface as i32).ingress().execute().await?;
+
+        let mut sel = netlink_packet_route::tc::u32::Sel::default();
+        sel.flags = netlink_packet_route::tc::constants::TC_U32_TERMINAL;
+        sel.nkeys = 1;
+        sel.keys = vec![netlink_packet_route::tc::u32::Key::default()];
+        handle.traffic_filter(interface as i32).add()
+            .parent(netlink::INGRESS_HANDLE)
+            // The protocol goes in network byte order.
+            .protocol(netlink::ETH_P_ALL.to_be())
+            .u32(vec![
+                netlink_packet_route::tc::u32::Nla::Sel(sel),
+                netlink_packet_route::tc::u32::Nla::Act(vec![netlink::police_action(rate / 8)]),
+            ])?
+            .execute().await?;
+    }
+    Ok(())
+}
+
+/// rtnetlink can't set the protocol of a neighbour entry, so the request is
+/// built by hand.
+async fn add_proxy(handle: &rtnetlink::Handle, route_proto: u8, p: AddProxy) -> Result<(), Error> {
+    let interface = netlink::interface_name_to_index(handle, &p.interface_name).await?;
+    let mut msg = netlink_packet_route::neighbour::NeighbourMessage::default();
+    msg.header.family = match p.address {
+        std::net::IpAddr::V4(_) => netlink_packet_route::constants::AF_INET as u8,
+        std::net::IpAddr::V6(_) => netlink_packet_route::constants::AF_INET6 as u8,
+    };
+    msg.header.ifindex = interface;
+    msg.header.state = netlink_packet_route::constants::NUD_PERMANENT;
+    msg.header.flags = netlink_packet_route::constants::NTF_PROXY;
+    msg.nlas.push(netlink_packet_route::nlas::neighbour::Nla::Destination(match p.address {
+        std::net::IpAddr::V4(v4) => v4.octets().to_vec(),
+        std::net::IpAddr::V6(v6) => v6.octets().to_vec(),
+    }));
+    msg.nlas.push(netlink_packet_route::nlas::neighbour::Nla::Other(
+        netlink_packet_utils::nla::DefaultNla::new(netlink::NDA_PROTOCOL, vec![route_proto])
+    ));
+    netlink::send(
+        handle, netlink_packet_route::RtnlMessage::NewNeighbour(msg),
+        netlink_packet_core::NLM_F_CREATE | netlink_packet_core::NLM_F_REPLACE,
+    ).await
+}
+
+async fn add_route(
+    handle: &rtnetlink::Handle,
+    route_proto: u8,
+    renames: &Renames,
+    r: AddRoute,
+    replace: bool,
+) -> Result<(), Error> {
+    let mut req = handle.route().add()
+        .protocol(route_proto)
+        .kind(r.kind)
+        .table_id(r.table);
+    if let Some(interface_name) = &r.interface_name {
+        let interface = renames.index(handle, interface_name).await?;
+        req = req.output_interface(interface);
+    }
+    if let Some(scope) = r.scope {
+        req = req.scope(scope);
+    }
+    if let Some(metric) = r.metric {
+        req.message_mut().nlas.push(netlink_packet_route::nlas::route::Nla::Priority(metric));
+    }
+    if let Some(mtu) = r.mtu {
+        req.message_mut().nlas.push(netlink_packet_route::nlas::route::Nla::Metrics(
+            netlink::emit_metric(netlink_packet_route::constants::RTAX_MTU, mtu)
+        ));
+    }
+    match r.destination {
+        std::net::IpAddr::V4(v4) => {
+            let mut req = req.v4()
+                .destination_prefix(v4, r.destination_prefix_length);
+            if let Some(std::net::IpAddr::V4(pref_src)) = r.pref_src {
+                req = req.pref_source(pref_src);
+            }
+            // v4() and v6() reset the replace flag, so it's set last.
+            if replace {
+                req = req.replace();
+            }
+            req.execute().await?;
+        }
+        std::net::IpAddr::V6(v6) => {
+            let mut req = req.v6()
+                .destination_prefix(v6, r.destination_prefix_length);
+            if let Some(std::net::IpAddr::V6(pref_src)) = r.pref_src {
+                req = req.pref_source(pref_src);
+            }
+            if replace {
+                req = req.replace();
+            }
+            req.execute().await?;
+        }
+    };
+    Ok(())
+}
+
+/// Interfaces by the names the plan gave them, whether or not the renames
+/// to them went through.
+#[derive(Default)]
+struct Renames {
+    /// The interface each rename was for, by the name it was to get.
+    targets: std::collections::HashMap<String, u32>,
+    /// The name each of those interfaces has now.
+    current: std::collections::HashMap<u32, String>,
+}
+
+impl Renames {
+    async fn index(&self, handle: &rtnetlink::Handle, name: &str) -> Result<u32, Error> {
+        match self.targets.get(name) {
+            Some(index) => Ok(*index),
+            None => netlink::interface_name_to_index(handle, name).await,
+        }
+    }
+
+    fn current_name<'a>(&'a self, name: &'a str) -> &'a str {
+        self.targets.get(name)
+            .and_then(|index| self.current.get(index))
+            .map_or(name, |n| n)
+    }
+}
+
+/// Apply a plan, returning the names of the interfaces the kernel refused to
+/// rename by the names they were to get. The rest of the plan still goes
+/// ahead for those, under their current names.
+pub async fn apply_diff(
+    handle: &rtnetlink::Handle,
+    route_proto: u8,
+    diff: Vec<Diff>,
+) -> Result<std::collections::HashMap<String, String>, Error> {
+    let mut renames = Renames::default();
+    let mut not_renamed = std::collections::HashMap::new();
     for command in diff {
         match command {
-            Diff::AddInterface(i) => {
-                handle.link().add()
-                    .vlan(i.name, i.link, i.vlan)
-                    .execute().await?;
+            Diff::AddInterface(i) => match i.kind {
+                InterfaceKind::Vlan { link_name, vlan, protocol } => {
+                    let link = renames.index(handle, &link_name).await?;
+                    let mut req = handle.link().add().vlan(i.name, link, vlan);
+                    if protocol != netlink::ETH_P_8021Q {
+                        set_vlan_protocol(req.message_mut(), protocol);
+                    }
+                    req.execute().await?;
+                }
+                InterfaceKind::Vxlan(vxlan) => {
+                    let mut req = handle.link().add()
+                        .vxlan(i.name, vxlan.vni)
+                        .port(vxlan.port)
+                        .up();
+                    req = match vxlan.local {
+                        Some(std::net::IpAddr::V4(local)) => req.local(local),
+                        Some(std::net::IpAddr::V6(local)) => req.local6(local),
+                        None => req,
+                    };
+                    req = match vxlan.remote {
+                        Some(std::net::IpAddr::V4(remote)) => req.remote(remote),
+                        Some(std::net::IpAddr::V6(remote)) => req.remote6(remote),
+                        None => req,
+                    };
+                    if let Some(link) = vxlan.link {
+                        req = req.link(link);
+                    }
+                    req.execute().await?;
+                }
             }
             Diff::RemoveInterface(i) => {
                 handle.link().del(i).execute().await?;
             }
+            Diff::RenameInterface(r) => {
+                let current = renames.current.remove(&r.index).unwrap_or(r.from);
+                renames.targets.insert(r.name.clone(), r.index);
+                match handle.link().set(r.index).name(r.name.clone()).execute().await {
+                    Ok(()) => {
+                        renames.current.insert(r.index, r.name);
+                    }
+                    // Kernels before 6.2 refuse to rename an interface that is
+                    // up, and taking it down would flush its routes. Everything
+                    // else goes on with the name it has, the rename is tried
+                    // again next time.
+                    Err(rtnetlink::Error::NetlinkError(e)) if e.to_io().raw_os_error() == Some(nix::errno::Errno::EBUSY as i32) => {
+                        warn!("Kernel can't rename interface {} to {} while it's up, keeping its name", current, r.name);
+                        not_renamed.insert(r.name, current.clone());
+                        renames.current.insert(r.index, current);
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            Diff::AddVrf(v) => {
+                let mut req = handle.link().add();
+                let msg = req.message_mut();
+                msg.header.flags = netlink_packet_route::IFF_UP;
+                msg.header.change_mask = netlink_packet_route::IFF_UP;
+                msg.nlas.push(netlink_packet_route::nlas::link::Nla::IfName(v.name));
+                msg.nlas.push(netlink_packet_route::nlas::link::Nla::Info(vec![
+                    netlink_packet_route::nlas::link::Info::Kind(
+                        netlink_packet_route::nlas::link::InfoKind::Vrf
+                    ),
+                    netlink_packet_route::nlas::link::Info::Data(
+                        netlink_packet_route::nlas::link::InfoData::Vrf(vec![
+                            netlink_packet_route::nlas::link::InfoVrf::TableId(v.table)
+                        ])
+                    ),
+                ]));
+                req.execute().await?;
+            }
+            Diff::SetMaster(m) => {
+                let interface = renames.index(handle, &m.interface_name).await?;
+                let req = handle.link().set(interface);
+                match m.master_name {
+                    Some(master_name) => {
+                        let master = netlink::interface_name_to_index(handle, &master_name).await?;
+                        req.master(master).execute().await?;
+                    }
+                    None => {
+                        req.nomaster().execute().await?;
+                    }
+                }
+            }
+            Diff::ModifyInterface(m) => {
+                let interface = renames.index(handle, &m.interface_name).await?;
+                let mut req = handle.link().set(interface);
+                if let Some(mtu) = m.mtu {
+                    req = req.mtu(mtu);
+                }
+                if let Some(mac) = m.mac {
+                    req = req.address(mac.0.to_vec());
+                }
+                req = match m.up {
+                    Some(true) => req.up(),
+                    Some(false) => req.down(),
+                    None => req,
+                };
+                req.execute().await?;
+            }
+            Diff::SetEgressLimit(l) => {
+                let interface = renames.index(handle, &l.interface_name).await?;
+                set_egress_limit(handle, interface, l).await?;
+            }
+            Diff::SetIngressLimit(l) => {
+                let interface = renames.index(handle, &l.interface_name).await?;
+                set_ingress_limit(handle, interface, l).await?;
+            }
             Diff::AddAddress(a) => {
-                let interface = netlink::interface_name_to_index(handle, &a.interface_name).await?;
+                let interface = renames.index(handle, &a.interface_name).await?;
                 handle.address()
                     .add(interface, a.address, a.prefix_length)
                     .execute().await?;
             }
             Diff::RemoveAddress(a) => {
                 handle.address()
-                    .del(a)
+                    .del(a.message)
                     .execute().await?;
             }
-            Diff::AddRoute(r) => {
-                let interface = netlink::interface_name_to_index(handle, &r.interface_name).await?;
-                let req = handle.route().add()
-                    .protocol(route_proto)
-                    .output_interface(interface);
-                match r.destination {
-                    std::net::IpAddr::V4(v4) => {
-                        req.v4()
-                            .destination_prefix(v4, r.destination_prefix_length)
-                            .execute().await?;
-                    }
-                    std::net::IpAddr::V6(v6) => {
-                        req.v6()
-                            .destination_prefix(v6, r.destination_prefix_length)
-                            .execute().await?;
-                    }
-                };
+            Diff::AddRoute(r) | Diff::AddUnassignedRoute(r) => {
+                add_route(handle, route_proto, &renames, r, false).await?;
+            }
+            Diff::ModifyRoute(r) => {
+                add_route(handle, route_proto, &renames, r, true).await?;
             }
-            Diff::RemoveRoute(msg) => {
+            Diff::RemoveRoute(r) | Diff::RemoveUnassignedRoute(r) => {
                 handle.route()
-                    .del(msg)
+                    .del(r.message)
+                    .execute().await?;
+            }
+            Diff::AddProxy(p) => {
+                add_proxy(handle, route_proto, p).await?;
+            }
+            Diff::RemoveProxy(p) => {
+                handle.neighbours()
+                    .del(p.message)
                     .execute().await?;
             }
+            Diff::SetSysctl(s) => {
+                let interface_name = renames.current_name(&s.interface_name);
+                sysctl::write(&sysctl::interface_path(&s.family, interface_name, &s.name), &s.value).await?;
+            }
         }
     }
 
-    Ok(())
-}
\ No newline at end of file
+    Ok(not_renamed)
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use netlink_packet_route::constants::{RTN_BLACKHOLE, RTN_UNICAST, RT_SCOPE_LINK, RT_SCOPE_UNIVERSE};
+
+    fn add_route(destination: &str, destination_prefix_length: u8) -> AddRoute {
+        AddRoute {
+            destination: destination.parse().unwrap(),
+            destination_prefix_length,
+            interface_name: Some("vps1001".to_string()),
+            kind: RTN_UNICAST,
+            table: 254,
+            metric: None,
+            pref_src: None,
+            mtu: None,
+            scope: None,
+        }
+    }
+
+    /// The route the kernel reports for `add_route` on interface 7.
+    fn route(destination: &str, destination_prefix_length: u8) -> netlink::Route {
+        let destination: std::net::IpAddr = destination.parse().unwrap();
+        netlink::Route {
+            destination,
+            destination_prefix_length,
+            interface: 7,
+            kind: RTN_UNICAST,
+            table: 254,
+            metric: if destination.is_ipv4() { 0 } else { IP6_RT_PRIO_USER },
+            pref_src: None,
+            mtu: None,
+            scope: RT_SCOPE_UNIVERSE,
+            message: Default::default(),
+        }
+    }
+
+    #[test]
+    fn fits_defaults() {
+        assert!(add_route("192.0.2.1", 32).fits(&route("192.0.2.1", 32), Some(7)));
+        assert!(add_route("2001:db8::", 64).fits(&route("2001:db8::", 64), Some(7)));
+    }
+
+    #[test]
+    fn fits_other_destination_or_interface() {
+        let wanted = add_route("192.0.2.1", 32);
+        assert!(!wanted.fits(&route("192.0.2.2", 32), Some(7)));
+        assert!(!wanted.fits(&route("192.0.2.0", 31), Some(7)));
+        assert!(!wanted.fits(&route("192.0.2.1", 32), Some(8)));
+        assert!(!wanted.fits(&route("192.0.2.1", 32), None));
+    }
+
+    #[test]
+    fn fits_attributes() {
+        let mut wanted = add_route("192.0.2.1", 32);
+        wanted.metric = Some(100);
+        assert!(!wanted.fits(&route("192.0.2.1", 32), Some(7)));
+        let mut existing = route("192.0.2.1", 32);
+        existing.metric = 100;
+        assert!(wanted.fits(&existing, Some(7)));
+
+        wanted.mtu = Some(1400);
+        assert!(!wanted.fits(&existing, Some(7)));
+        existing.mtu = Some(1400);
+        assert!(wanted.fits(&existing, Some(7)));
+
+        wanted.scope = Some(RT_SCOPE_LINK);
+        assert!(!wanted.fits(&existing, Some(7)));
+        existing.scope = RT_SCOPE_LINK;
+        assert!(wanted.fits(&existing, Some(7)));
+    }
+
+    #[test]
+    fn fits_ignores_ipv6_scope() {
+        let mut wanted = add_route("2001:db8::", 64);
+        wanted.scope = Some(RT_SCOPE_LINK);
+        assert!(wanted.fits(&route("2001:db8::", 64), Some(7)));
+    }
+
+    #[test]
+    fn fits_blackhole_on_any_interface() {
+        let mut wanted = add_route("192.0.2.1", 32);
+        wanted.kind = RTN_BLACKHOLE;
+        wanted.interface_name = None;
+        let mut existing = route("192.0.2.1", 32);
+        assert!(!wanted.fits(&existing, None));
+        existing.kind = RTN_BLACKHOLE;
+        existing.interface = 0;
+        assert!(wanted.fits(&existing, None));
+    }
+
+    #[test]
+    fn replaces_same_key() {
+        let mut wanted = add_route("192.0.2.1", 32);
+        wanted.kind = RTN_BLACKHOLE;
+        wanted.mtu = Some(1400);
+        assert!(wanted.replaces(&route("192.0.2.1", 32)));
+        assert!(!wanted.fits(&route("192.0.2.1", 32), Some(7)));
+    }
+
+    #[test]
+    fn replaces_other_key() {
+        let wanted = add_route("192.0.2.1", 32);
+        assert!(!wanted.replaces(&route("192.0.2.2", 32)));
+        let mut existing = route("192.0.2.1", 32);
+        existing.table = 100;
+        assert!(!wanted.replaces(&existing));
+        let mut existing = route("192.0.2.1", 32);
+        existing.metric = 100;
+        assert!(!wanted.replaces(&existing));
+        assert!(!add_route("2001:db8::", 64).replaces(&route("2001:db8::", 48)));
+    }
+
+    #[test]
+    fn renames_current_name() {
+        let mut renames = Renames::default();
+        renames.targets.insert("vps1001".to_string(), 7);
+        renames.current.insert(7, "vps1".to_string());
+        renames.targets.insert("vps1002".to_string(), 8);
+        renames.current.insert(8, "vps1002".to_string());
+        assert_eq!(renames.current_name("vps1001"), "vps1");
+        assert_eq!(renames.current_name("vps1002"), "vps1002");
+        assert_eq!(renames.current_name("eth0"), "eth0");
+    }
+}
@@ -0,0 +1,248 @@
+use std::collections::HashMap;
+use tera::Value;
+
+/// Add filters for address arithmetic. Each takes an address, or a prefix as
+/// `address/length`; those working on a prefix take the length from a
+/// `prefix` argument when given a bare address.
+pub fn register(tera: &mut tera::Tera) {
+    tera.register_filter("peer_of_31", peer_of_31);
+    tera.register_filter("network", network);
+    tera.register_filter("broadcast", broadcast);
+    tera.register_filter("nth_host", nth_host);
+    tera.register_filter("prefix_len", prefix_len);
+    tera.register_filter("reverse_dns", reverse_dns);
+    tera.register_filter("is_ipv6", is_ipv6);
+}
+
+/// An address as an integer, so both families can share the arithmetic.
+struct Address {
+    bits: u128,
+    v6: bool,
+    prefix_len: Option<u8>,
+}
+
+impl Address {
+    fn width(&self) -> u8 {
+        if self.v6 { 128 } else { 32 }
+    }
+
+    fn mask(&self, prefix_len: u8) -> u128 {
+        let host_bits = (self.width() - prefix_len) as u32;
+        let all = u128::MAX >> (128 - self.width() as u32);
+        all & !(u128::MAX.checked_shr(128 - host_bits).unwrap_or(0))
+    }
+
+    fn to_ip(&self, bits: u128) -> std::net::IpAddr {
+        if self.v6 {
+            std::net::IpAddr::V6(std::net::Ipv6Addr::from(bits))
+        } else {
+            std::net::IpAddr::V4(std::net::Ipv4Addr::from(bits as u32))
+        }
+    }
+}
+
+fn parse(name: &str, value: &Value) -> tera::Result<Address> {
+    let s = value.as_str()
+        .ok_or_else(|| tera::Error::msg(format!("{} expects an address, got {}", name, value)))?;
+    let (address, prefix_len) = match s.split_once('/') {
+        Some((a, l)) => (a, Some(l)),
+        None => (s, None),
+    };
+    let address: std::net::IpAddr = address.parse()
+        .map_err(|_| tera::Error::msg(format!("{}: invalid address {}", name, s)))?;
+    let (bits, v6) = match address {
+        std::net::IpAddr::V4(a) => (u32::from(a) as u128, false),
+        std::net::IpAddr::V6(a) => (u128::from(a), true),
+    };
+    let mut address = Address {
+        bits,
+        v6,
+        prefix_len: None,
+    };
+    if let Some(l) = prefix_len {
+        address.prefix_len = Some(l.parse().ok()
+            .filter(|l| *l <= address.width())
+            .ok_or_else(|| tera::Error::msg(format!("{}: invalid prefix {}", name, s)))?);
+    }
+    Ok(address)
+}
+
+/// The prefix length from the value itself or the `prefix` argument.
+fn prefix_arg(name: &str, address: &Address, args: &HashMap<String, Value>) -> tera::Result<u8> {
+    let prefix_len = match (address.prefix_len, args.get("prefix")) {
+        (Some(l), _) => Some(l as u64),
+        (None, Some(l)) => l.as_u64(),
+        (None, None) => return Err(tera::Error::msg(format!("{} needs a prefix length", name))),
+    };
+    prefix_len.filter(|l| *l <= address.width() as u64)
+        .map(|l| l as u8)
+        .ok_or_else(|| tera::Error::msg(format!("{}: invalid prefix length", name)))
+}
+
+/// The other address of a /31, or of an IPv6 /127.
+fn peer_of_31(value: &Value, _: &HashMap<String, Value>) -> tera::Result<Value> {
+    let address = parse("peer_of_31", value)?;
+    Ok(Value::String(address.to_ip(address.bits ^ 1).to_string()))
+}
+
+/// The first address of the prefix.
+fn network(value: &Value, args: &HashMap<String, Value>) -> tera::Result<Value> {
+    let address = parse("network", value)?;
+    let prefix_len = prefix_arg("network", &address, args)?;
+    Ok(Value::String(address.to_ip(address.bits & address.mask(prefix_len)).to_string()))
+}
+
+/// The last address of the prefix.
+fn broadcast(value: &Value, args: &HashMap<String, Value>) -> tera::Result<Value> {
+    let address = parse("broadcast", value)?;
+    let prefix_len = prefix_arg("broadcast", &address, args)?;
+    let mask = address.mask(prefix_len);
+    let all = address.mask(address.width());
+    Ok(Value::String(address.to_ip((address.bits & mask) | (all & !mask)).to_string()))
+}
+
+/// The `n`th address of the prefix, counting the network address as 0.
+fn nth_host(value: &Value, args: &HashMap<String, Value>) -> tera::Result<Value> {
+    let address = parse("nth_host", value)?;
+    let prefix_len = prefix_arg("nth_host", &address, args)?;
+    let n = args.get("n").and_then(|n| n.as_u64())
+        .ok_or_else(|| tera::Error::msg("nth_host needs a number n"))? as u128;
+    let host_bits = (address.width() - prefix_len) as u32;
+    if host_bits < 128 && n >> host_bits != 0 {
+        return Err(tera::Error::msg(format!("nth_host: {} is outside a /{}", n, prefix_len)));
+    }
+    Ok(Value::String(address.to_ip((address.bits & address.mask(prefix_len)) | n).to_string()))
+}
+
+/// The length of a prefix, or the full width of a bare address.
+fn prefix_len(value: &Value, _: &HashMap<String, Value>) -> tera::Result<Value> {
+    let address = parse("prefix_len", value)?;
+    Ok(Value::from(address.prefix_len.unwrap_or(address.width())))
+}
+
+/// The PTR name of an address, or the reverse zone of a prefix, which has to
+/// end on an octet (IPv4) or nibble (IPv6) boundary.
+fn reverse_dns(value: &Value, _: &HashMap<String, Value>) -> tera::Result<Value> {
+    let address = parse("reverse_dns", value)?;
+    let prefix_len = address.prefix_len.unwrap_or(address.width());
+    let (step, suffix) = if address.v6 { (4, "ip6.arpa") } else { (8, "in-addr.arpa") };
+    if prefix_len % step != 0 {
+        return Err(tera::Error::msg(format!("reverse_dns: /{} doesn't end on a boundary of {} bits", prefix_len, step)));
+    }
+
+    let mut labels = (0..prefix_len / step)
+        .map(|i| {
+            let shift = (address.width() - (i + 1) * step) as u32;
+            let part = (address.bits >> shift) & ((1 << step) - 1);
+            if address.v6 { format!("{:x}", part) } else { part.to_string() }
+        })
+        .collect::<Vec<_>>();
+    labels.reverse();
+    labels.push(suffix.to_string());
+    Ok(Value::String(labels.join(".")))
+}
+
+fn is_ipv6(value: &Value, _: &HashMap<String, Value>) -> tera::Result<Value> {
+    Ok(Value::Bool(parse("is_ipv6", value)?.v6))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Filter = fn(&Value, &HashMap<String, Value>) -> tera::Result<Value>;
+
+    fn apply(filter: Filter, value: &str, args: &[(&str, Value)]) -> tera::Result<Value> {
+        let args = args.iter().map(|(k, v)| (k.to_string(), v.clone())).collect();
+        filter(&Value::from(value), &args)
+    }
+
+    fn ok(filter: Filter, value: &str, args: &[(&str, Value)]) -> String {
+        apply(filter, value, args).unwrap().as_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn peer_of_31_both_ends() {
+        assert_eq!(ok(peer_of_31, "192.0.2.4", &[]), "192.0.2.5");
+        assert_eq!(ok(peer_of_31, "192.0.2.5", &[]), "192.0.2.4");
+        assert_eq!(ok(peer_of_31, "192.0.2.5/31", &[]), "192.0.2.4");
+        assert_eq!(ok(peer_of_31, "2001:db8::1", &[]), "2001:db8::");
+        assert!(apply(peer_of_31, "192.0.2.256", &[]).is_err());
+    }
+
+    #[test]
+    fn broadcast_prefix() {
+        assert_eq!(ok(broadcast, "192.0.2.17/28", &[]), "192.0.2.31");
+        assert_eq!(ok(broadcast, "192.0.2.17", &[("prefix", Value::from(24))]), "192.0.2.255");
+        assert_eq!(ok(broadcast, "192.0.2.4/31", &[]), "192.0.2.5");
+        assert_eq!(ok(broadcast, "192.0.2.4/32", &[]), "192.0.2.4");
+        assert_eq!(ok(broadcast, "192.0.2.4/0", &[]), "255.255.255.255");
+        assert_eq!(ok(broadcast, "2001:db8::/64", &[]), "2001:db8::ffff:ffff:ffff:ffff");
+        assert!(apply(broadcast, "192.0.2.4", &[]).is_err());
+        assert!(apply(broadcast, "192.0.2.4", &[("prefix", Value::from(33))]).is_err());
+    }
+
+    #[test]
+    fn network_prefix() {
+        assert_eq!(ok(network, "192.0.2.17/28", &[]), "192.0.2.16");
+        assert_eq!(ok(network, "192.0.2.17", &[("prefix", Value::from(24))]), "192.0.2.0");
+        assert_eq!(ok(network, "192.0.2.5/32", &[]), "192.0.2.5");
+        assert_eq!(ok(network, "192.0.2.5/0", &[]), "0.0.0.0");
+        assert_eq!(ok(network, "2001:db8:1:2::1/48", &[]), "2001:db8:1::");
+        assert_eq!(ok(network, "2001:db8::1", &[("prefix", Value::from(64))]), "2001:db8::");
+        assert!(apply(network, "192.0.2.5", &[]).is_err());
+        assert!(apply(network, "2001:db8::1", &[("prefix", Value::from(129))]).is_err());
+    }
+
+    #[test]
+    fn prefix_len_of() {
+        let len = |value| apply(prefix_len, value, &[]).unwrap().as_u64().unwrap();
+        assert_eq!(len("192.0.2.0/24"), 24);
+        assert_eq!(len("192.0.2.1"), 32);
+        assert_eq!(len("0.0.0.0/0"), 0);
+        assert_eq!(len("2001:db8::/48"), 48);
+        assert_eq!(len("2001:db8::1"), 128);
+        assert!(apply(prefix_len, "192.0.2.0/33", &[]).is_err());
+    }
+
+    #[test]
+    fn is_ipv6_of() {
+        let v6 = |value| apply(is_ipv6, value, &[]).unwrap().as_bool().unwrap();
+        assert!(!v6("192.0.2.1"));
+        assert!(!v6("192.0.2.0/24"));
+        assert!(v6("2001:db8::1"));
+        assert!(v6("::/0"));
+        assert!(apply(is_ipv6, "example.com", &[]).is_err());
+        assert!(is_ipv6(&Value::from(6), &HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn nth_host_prefix() {
+        assert_eq!(ok(nth_host, "192.0.2.17/24", &[("n", Value::from(1))]), "192.0.2.1");
+        assert_eq!(ok(nth_host, "2001:db8::/64", &[("n", Value::from(255))]), "2001:db8::ff");
+        assert_eq!(ok(nth_host, "192.0.2.5/31", &[("n", Value::from(0))]), "192.0.2.4");
+        assert_eq!(ok(nth_host, "192.0.2.5/31", &[("n", Value::from(1))]), "192.0.2.5");
+        assert!(apply(nth_host, "192.0.2.5/31", &[("n", Value::from(2))]).is_err());
+        assert_eq!(ok(nth_host, "192.0.2.5/32", &[("n", Value::from(0))]), "192.0.2.5");
+        assert!(apply(nth_host, "192.0.2.5/32", &[("n", Value::from(1))]).is_err());
+        assert!(apply(nth_host, "192.0.2.0/24", &[]).is_err());
+    }
+
+    #[test]
+    fn reverse_dns_ipv4() {
+        assert_eq!(ok(reverse_dns, "192.0.2.5", &[]), "5.2.0.192.in-addr.arpa");
+        assert_eq!(ok(reverse_dns, "192.0.2.0/24", &[]), "2.0.192.in-addr.arpa");
+        assert!(apply(reverse_dns, "192.0.2.0/25", &[]).is_err());
+    }
+
+    #[test]
+    fn reverse_dns_ipv6() {
+        assert_eq!(
+            ok(reverse_dns, "2001:db8::1", &[]),
+            "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa",
+        );
+        assert_eq!(ok(reverse_dns, "2001:db8:12::/48", &[]), "2.1.0.0.8.b.d.0.1.0.0.2.ip6.arpa");
+        assert_eq!(ok(reverse_dns, "2001:db8::/36", &[]), "0.8.b.d.0.1.0.0.2.ip6.arpa");
+        assert!(apply(reverse_dns, "2001:db8::/50", &[]).is_err());
+    }
+}
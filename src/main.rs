@@ -1,22 +1,63 @@
 #[macro_use]
 extern crate log;
 
-use clap::Parser;
+use clap::{FromArgMatches, Parser};
 
 mod config;
 mod netlink;
 mod diff;
+mod nftables;
+mod sysctl;
+mod filters;
 
+// Only describes the command line, see `main` for how it's parsed.
 #[derive(Parser, Debug)]
-struct Args {
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    args: Args,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Rewrite config files of older versions in the newest one. This drops
+    /// comments, and YAML anchors are written out in full.
+    MigrateConfig(ConfigArgs),
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct ConfigArgs {
+    #[arg(long = "config")]
+    path: std::path::PathBuf,
+    /// Format of the config file, by default going by its extension and
+    /// falling back to JSON
+    #[arg(long = "config-format", value_enum)]
+    format: Option<config::Format>,
+    /// Directory of further config files, each one VPS, going by its
+    /// `v4_addr`, or a partial config, merged into the main config in the order of their names
+    #[arg(long = "config-dir")]
+    dir: Option<std::path::PathBuf>,
+    /// Only warn about fields of the config that aren't known, as for a
+    /// config written for a newer version
     #[arg(long)]
-    config: std::path::PathBuf,
+    allow_unknown_fields: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct Args {
+    #[command(flatten)]
+    config: ConfigArgs,
     #[arg(long)]
     templates: String,
     #[arg(long)]
     radvd: std::path::PathBuf,
     #[arg(long)]
     kea: std::path::PathBuf,
+    /// Path to nft, to load anti-spoofing rules for the VPS interfaces
+    #[arg(long)]
+    nft: Option<std::path::PathBuf>,
 }
 
 #[derive(Debug)]
@@ -25,6 +66,29 @@ enum Error {
     Tera(tera::Error),
     Io(std::io::Error),
     InterfaceNotFound(String),
+    Nftables(String),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Netlink(e) => write!(f, "netlink error: {}", e),
+            Self::Tera(e) => {
+                // Tera keeps what actually went wrong, like an undefined
+                // variable, in the source of the error.
+                write!(f, "template error: {}", e)?;
+                let mut source = std::error::Error::source(e);
+                while let Some(e) = source {
+                    write!(f, ": {}", e)?;
+                    source = e.source();
+                }
+                Ok(())
+            }
+            Self::Io(e) => write!(f, "IO error: {}", e),
+            Self::InterfaceNotFound(name) => write!(f, "interface {} not found", name),
+            Self::Nftables(e) => write!(f, "nftables error: {}", e),
+        }
+    }
 }
 
 impl From<rtnetlink::Error> for Error {
@@ -50,69 +114,292 @@ impl From<tera::Error> for Error {
     }
 }
 
+/// A config file as parsed and migrated to the newest version.
+struct ConfigFile {
+    path: std::path::PathBuf,
+    format: config::Format,
+    value: serde_json::Value,
+    /// Whether migrating changed anything.
+    migrated: bool,
+}
+
+async fn read_config_files(args: &ConfigArgs) -> Result<Vec<ConfigFile>, String> {
+    let format = args.format
+        .or_else(|| config::Format::from_path(&args.path))
+        .unwrap_or(config::Format::Json);
+    let config_file = tokio::fs::read(&args.path).await
+        .map_err(|e| format!("Failed to open config file: {}", e))?;
+    let mut value: serde_json::Value = format.parse(&config_file)
+        .map_err(|e| format!("Failed to parse config file: {}", e))?;
+    // The files of the config directory go along with the main config, so
+    // they're of its version.
+    let version = config::version(&value)
+        .map_err(|e| format!("Invalid config file: {}", e))?;
+    let original = value.clone();
+    config::migrate(&mut value)
+        .map_err(|e| format!("Invalid config file: {}", e))?;
+    let mut files = vec![ConfigFile {
+        path: args.path.clone(),
+        format,
+        migrated: value != original,
+        value,
+    }];
+
+    let Some(dir) = &args.dir else {
+        return Ok(files);
+    };
+    let mut dir_files = vec![];
+    let mut entries = tokio::fs::read_dir(dir).await
+        .map_err(|e| format!("Failed to open config directory: {}", e))?;
+    while let Some(entry) = entries.next_entry().await
+        .map_err(|e| format!("Failed to read config directory: {}", e))? {
+        let path = entry.path();
+        // Hidden files are left alone silently, as that's where temporary
+        // files written to be renamed into place go. Anything else left out
+        // is likely a misnamed VPS.
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        match config::Format::from_path(&path) {
+            Some(format) => dir_files.push((path, format)),
+            None => warn!("Skipping {}, which isn't named like a config file", path.display()),
+        }
+    }
+    dir_files.sort_by(|a, b| a.0.cmp(&b.0));
+    for (path, format) in dir_files {
+        let file = tokio::fs::read(&path).await
+            .map_err(|e| format!("Failed to open config file {}: {}", path.display(), e))?;
+        let mut value: serde_json::Value = format.parse(&file)
+            .map_err(|e| format!("Failed to parse config file {}: {}", path.display(), e))?;
+        let original = value.clone();
+        config::migrate_file(&mut value, version)
+            .map_err(|e| format!("Invalid config file {}: {}", path.display(), e))?;
+        files.push(ConfigFile {
+            path,
+            format,
+            migrated: value != original,
+            value,
+        });
+    }
+    Ok(files)
+}
+
+async fn load_config(args: &ConfigArgs) -> Result<config::Config, String> {
+    let files = read_config_files(args).await?
+        .into_iter()
+        .map(|f| (f.path, f.value))
+        .collect::<Vec<_>>();
+    config::Config::from_files(&files, args.allow_unknown_fields).map_err(|e| format!("Invalid config: {}", e))
+}
+
+/// Rewrite the config files that migrating changed, once the whole config is
+/// known to load.
+async fn migrate_config(args: &ConfigArgs) -> Result<(), String> {
+    let files = read_config_files(args).await?;
+    let values = files.iter()
+        .map(|f| (f.path.clone(), f.value.clone()))
+        .collect::<Vec<_>>();
+    config::Config::from_files(&values, args.allow_unknown_fields).map_err(|e| format!("Invalid config: {}", e))?;
+
+    for file in files.iter().filter(|f| f.migrated) {
+        let mut value = file.value.clone();
+        config::strip_nulls(&mut value);
+        let data = file.format.serialize(&value)
+            .map_err(|e| format!("Failed to write {}: {}", file.path.display(), e))?;
+        // Renamed into place, so a running instance never reads half a file.
+        let mut temp_path = file.path.clone().into_os_string();
+        temp_path.push(".tmp");
+        tokio::fs::write(&temp_path, data).await
+            .map_err(|e| format!("Failed to write {}: {}", file.path.display(), e))?;
+        tokio::fs::rename(&temp_path, &file.path).await
+            .map_err(|e| format!("Failed to write {}: {}", file.path.display(), e))?;
+        info!("Migrated {} to version {}", file.path.display(), config::VERSION);
+    }
+    Ok(())
+}
+
+/// Reload the config and the templates together, so neither is swapped in
+/// when the templates don't render with the new config.
 async fn handle_signals(
     mut signals: tokio::signal::unix::Signal,
-    config_path: std::path::PathBuf,
-    config: std::sync::Arc<tokio::sync::Mutex<config::Config>>
+    config_args: ConfigArgs,
+    config: std::sync::Arc<tokio::sync::Mutex<config::Config>>,
+    templates: std::sync::Arc<tokio::sync::Mutex<tera::Tera>>,
+    builtin_outputs: Vec<config::TemplateOutput>,
 ) {
     while let Some(()) = signals.recv().await {
-        let config_file = match tokio::fs::read(&config_path).await {
-            Ok(f) => f,
-            Err(e) => {
-                error!("Failed to open config file: {}", e);
-                continue;
-            }
-        };
-        let new_config: config::Config = match serde_json::from_slice(&config_file) {
+        let new_config = match load_config(&config_args).await {
             Ok(c) => c,
             Err(e) => {
-                error!("Failed to parse config file: {}", e);
+                error!("{}", e);
                 continue;
             }
         };
-        *config.lock().await = new_config;
-        info!("Config reloaded");
+        let mut new_templates = templates.lock().await.clone();
+        if let Err(e) = new_templates.full_reload() {
+            error!("Failed to reload templates: {}", Error::from(e));
+            continue;
+        }
+        if let Err(e) = check_templates(&new_templates, &new_config, &builtin_outputs) {
+            error!("Templates don't render with the new config: {}", e);
+            continue;
+        }
+        let mut config = config.lock().await;
+        *config = new_config;
+        *templates.lock().await = new_templates;
+        info!("Config and templates reloaded");
     }
 }
 
-struct ConfigPaths<'a> {
-    radvd: &'a std::path::Path,
-    kea: &'a std::path::Path,
+/// Render every template for the configured VPSes without writing anything,
+/// to catch missing templates and variables before they're needed.
+fn check_templates(
+    templates: &tera::Tera,
+    config: &config::Config,
+    builtin_outputs: &[config::TemplateOutput],
+) -> Result<(), Error> {
+    let interfaces = config.vps.iter()
+        .filter(|v| v.state != config::VPSState::Suspended)
+        .map(|v| diff::InterfaceState::new(v.interface_name(), v))
+        .collect::<Vec<_>>();
+    let context = template_context(&interfaces, &config.template_vars);
+    for output in builtin_outputs.iter().chain(&config.outputs) {
+        templates.render(&output.template, &context)?;
+    }
+    Ok(())
+}
+
+/// What has to be told about an update once the config lock is released.
+#[derive(Default)]
+struct Reload {
+    daemons: Vec<config::Daemon>,
+    commands: Vec<Vec<String>>,
 }
 
 async fn update(
     handle: &rtnetlink::Handle,
     templates: &tera::Tera,
     config: &config::Config,
-    config_paths: ConfigPaths<'_>,
+    builtin_outputs: &[config::TemplateOutput],
+    mut firewall: Option<&mut nftables::Firewall>,
     first_update: bool,
-) -> Result<bool, Error> {
-    let state = netlink::get_state(&handle, config.rt_proto).await?;
-    let (diff, interfaces) = diff::make_diff(&handle, &config.interface, &config.vps, state).await?;
+) -> Result<Reload, Error> {
+    let state = netlink::get_state(handle, config.rt_proto).await?;
+    let (diff, mut interfaces) = diff::make_diff(handle, config, state).await?;
+
+    // The interfaces are still worth reconciling with the firewall broken.
+    if let Some(firewall) = firewall.as_deref_mut() {
+        if let Err(err) = firewall.update(&interfaces).await {
+            error!("Failed to update firewall: {}", err);
+        }
+    }
 
+    // The daemons have to be told about interfaces coming and going even if
+    // their config stays the same, but not about a rename that's still
+    // refused, as it is on every update.
+    let mut reload = Reload::default();
     if !diff.is_empty() || first_update {
         info!("Updating interfaces");
-        diff::apply_diff(&handle, config.rt_proto, diff).await?;
-        update_config(templates, "radvd.tera", config_paths.radvd, &interfaces).await?;
-        update_config(templates, "kea.tera", config_paths.kea, &interfaces).await?;
+        for command in &diff {
+            info!("{}", command);
+        }
+        let planned = diff.len();
+        let not_renamed = diff::apply_diff(handle, config.rt_proto, diff).await?;
+        if not_renamed.len() < planned || first_update {
+            reload.daemons.extend([config::Daemon::Radvd, config::Daemon::Kea]);
+        }
+        if !not_renamed.is_empty() {
+            for interface in &mut interfaces {
+                if let Some(name) = not_renamed.get(&interface.name) {
+                    interface.name = name.clone();
+                }
+            }
+            if let Some(firewall) = firewall {
+                if let Err(err) = firewall.update(&interfaces).await {
+                    error!("Failed to update firewall: {}", err);
+                }
+            }
+        }
+    }
 
-        Ok(true)
-    } else {
-        Ok(false)
+    // Settings only the templates use, like DHCP options, change the config
+    // files without touching any interface, so they're rendered every time.
+    // Suspended VPSes get neither addresses nor router advertisements.
+    // From here on the interfaces have changed, so errors are only logged to
+    // not lose track of the daemons to reload.
+    let mut interfaces = interfaces.into_iter()
+        .filter(|i| i.vps.state != config::VPSState::Suspended)
+        .collect::<Vec<_>>();
+    match netlink::get_link_details(handle).await {
+        Ok(links) => {
+            for interface in &mut interfaces {
+                if let Some(link) = links.get(&interface.name) {
+                    interface.set_link(link);
+                }
+            }
+        }
+        Err(err) => error!("Failed to get link details: {}", err),
+    }
+    for output in builtin_outputs.iter().chain(&config.outputs) {
+        match update_config(templates, &output.template, &output.output, &interfaces, &config.template_vars).await {
+            Ok(true) => {}
+            Ok(false) => continue,
+            Err(err) => {
+                error!("Failed to update {}: {}", output.output.display(), err);
+                continue;
+            }
+        }
+        if let Some(daemon) = output.signal_daemon {
+            if !reload.daemons.contains(&daemon) {
+                reload.daemons.push(daemon);
+            }
+        }
+        if let Some(command) = &output.reload_command {
+            reload.commands.push(command.clone());
+        }
     }
+
+    Ok(reload)
 }
 
+/// Render a template into a config file, returning whether the file changed.
 async fn update_config(
     templates: &tera::Tera,
     template: &str,
     config_file: &std::path::Path,
-    interfaces: &[diff::InterfaceState<'_>]
-) -> Result<(), Error>  {
+    interfaces: &[diff::InterfaceState<'_>],
+    vars: &serde_json::Map<String, serde_json::Value>,
+) -> Result<bool, Error>  {
+    let config = templates.render(template, &template_context(interfaces, vars))?;
+    if tokio::fs::read_to_string(config_file).await.is_ok_and(|c| c == config) {
+        return Ok(false);
+    }
+    tokio::fs::write(config_file, config).await?;
+    Ok(true)
+}
+
+fn template_context(
+    interfaces: &[diff::InterfaceState<'_>],
+    vars: &serde_json::Map<String, serde_json::Value>,
+) -> tera::Context {
     let mut context = tera::Context::new();
     context.insert("interfaces", interfaces);
-    let config = templates.render(template, &context)?;
-    tokio::fs::write(config_file, config).await?;
-    Ok(())
+    context.insert("vars", vars);
+    context
+}
+
+async fn run_reload_command(command: &[String]) {
+    info!("Running {}", command.join(" "));
+    match tokio::process::Command::new(&command[0]).args(&command[1..]).status().await {
+        Ok(s) => {
+            if !s.success() {
+                warn!("{} exited with code: {}", command[0], s);
+            }
+        }
+        Err(err) => {
+            error!("Failed to run {}: {}", command[0], err);
+        }
+    }
 }
 
 async fn run_radvd(
@@ -187,14 +474,31 @@ async fn run_kea(
 #[tokio::main]
 async fn main() {
     pretty_env_logger::init();
-    let args = Args::parse();
+    // The arguments to run are required unless there's a subcommand, so
+    // they're only taken from the matches without one.
+    let matches = <Cli as clap::CommandFactory>::command().get_matches();
+    if matches.subcommand().is_some() {
+        let Command::MigrateConfig(config_args) = Command::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+        if let Err(e) = migrate_config(&config_args).await {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
 
-    let tera = tera::Tera::new(&args.templates).expect("Unable to setup Tera");
+    let mut tera = tera::Tera::new(&args.templates).expect("Unable to setup Tera");
+    filters::register(&mut tera);
 
     let signals = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()).expect("Unable to create signal listener");
 
-    let config_file = tokio::fs::read(&args.config).await.expect("Unable to open config file");
-    let config: config::Config = serde_json::from_slice(&config_file).expect("Unable to parse config file");
+    let config = match load_config(&args.config).await {
+        Ok(c) => c,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
     info!("Config loaded");
 
     let radvd_config_file = tempfile::Builder::new()
@@ -207,14 +511,40 @@ async fn main() {
     let (conn, handle, mut _messages) = rtnetlink::new_connection().expect("Unable to open netlink");
     tokio::spawn(conn);
 
-    if let Err(err) = update(&handle, &tera, &config, ConfigPaths {
-        radvd: radvd_config_file.path(),
-        kea: kea_config_file.path(),
-    }, true).await {
-        error!("Failed to run first update: {:?}", err);
+    let mut firewall = args.nft.clone().map(nftables::Firewall::new);
+
+    let builtin_outputs = [
+        config::TemplateOutput {
+            template: "radvd.tera".to_string(),
+            output: radvd_config_file.path().to_path_buf(),
+            reload_command: None,
+            signal_daemon: Some(config::Daemon::Radvd),
+        },
+        config::TemplateOutput {
+            template: "kea.tera".to_string(),
+            output: kea_config_file.path().to_path_buf(),
+            reload_command: None,
+            signal_daemon: Some(config::Daemon::Kea),
+        },
+    ];
+
+    if let Err(err) = check_templates(&tera, &config, &builtin_outputs) {
+        error!("Templates don't render: {}", err);
         return;
     }
 
+    match update(&handle, &tera, &config, &builtin_outputs, firewall.as_mut(), true).await {
+        Ok(reload) => {
+            for command in &reload.commands {
+                run_reload_command(command).await;
+            }
+        }
+        Err(err) => {
+            error!("Failed to run first update: {}", err);
+            return;
+        }
+    }
+
     let radvd_config_file_path = radvd_config_file.path().to_path_buf();
     let kea_config_file_path = kea_config_file.path().to_path_buf();
     let radvd_pid = std::sync::Arc::new(std::sync::atomic::AtomicU32::new(0));
@@ -230,31 +560,37 @@ async fn main() {
 
     let config = std::sync::Arc::new(tokio::sync::Mutex::new(config));
 
-    tokio::spawn(handle_signals(signals, args.config.clone(), config.clone()));
+    let templates = std::sync::Arc::new(tokio::sync::Mutex::new(tera));
+
+    tokio::spawn(handle_signals(signals, args.config.clone(), config.clone(), templates.clone(), builtin_outputs.to_vec()));
 
     loop {
         tokio::time::sleep(std::time::Duration::from_secs(5)).await;
         let config = config.lock().await;
-        let did_update = match update(&handle, &tera, &config, ConfigPaths {
-            radvd: radvd_config_file.path(),
-            kea: kea_config_file.path(),
-        }, false).await {
+        let templates = templates.lock().await;
+        let reload = match update(&handle, &templates, &config, &builtin_outputs, firewall.as_mut(), false).await {
             Ok(d) => d,
             Err(err) => {
-                error!("Failed to run update: {:?}", err);
+                error!("Failed to run update: {}", err);
                 continue;
             }
         };
+        drop(templates);
         drop(config);
-        if did_update {
+        for command in &reload.commands {
+            run_reload_command(command).await;
+        }
+        if !reload.daemons.is_empty() {
             tokio::time::sleep(std::time::Duration::from_secs(10)).await;
-            let radvd_pid = nix::unistd::Pid::from_raw(radvd_pid.load(std::sync::atomic::Ordering::Relaxed) as i32);
-            if let Err(err) = nix::sys::signal::kill(radvd_pid, nix::sys::signal::Signal::SIGHUP) {
-                warn!("Failed to reload radvd: {}", err);
-            }
-            let kea_pid = nix::unistd::Pid::from_raw(kea_pid.load(std::sync::atomic::Ordering::Relaxed) as i32);
-            if let Err(err) = nix::sys::signal::kill(kea_pid, nix::sys::signal::Signal::SIGHUP) {
-                warn!("Failed to reload kea: {}", err);
+        }
+        for daemon in reload.daemons {
+            let (name, pid) = match daemon {
+                config::Daemon::Radvd => ("radvd", &radvd_pid),
+                config::Daemon::Kea => ("kea", &kea_pid),
+            };
+            let pid = nix::unistd::Pid::from_raw(pid.load(std::sync::atomic::Ordering::Relaxed) as i32);
+            if let Err(err) = nix::sys::signal::kill(pid, nix::sys::signal::Signal::SIGHUP) {
+                warn!("Failed to reload {}: {}", name, err);
             }
         }
     }
@@ -1,15 +1,72 @@
-use futures_util::TryStreamExt;
+use futures_util::{StreamExt, TryStreamExt};
+use netlink_packet_utils::nla::Nla;
 use crate::Error;
 
+pub const ETH_P_8021Q: u16 = 0x8100;
+pub const ETH_P_8021AD: u16 = 0x88a8;
+pub const ETH_P_ALL: u16 = 0x0003;
+
+const TCA_TBF_PARMS: u16 = 1;
+const TCA_TBF_RATE64: u16 = 4;
+const TCA_TBF_BURST: u16 = 6;
+const TCA_POLICE_TBF: u16 = 1;
+const TCA_POLICE_RATE: u16 = 2;
+const TCA_POLICE_RATE64: u16 = 8;
+const TC_LINKLAYER_ETHERNET: u8 = 1;
+const TC_RTAB_SIZE: usize = 256;
+/// Packet sizes in a rate table go up in steps of 1 << RTAB_CELL_LOG bytes.
+const RTAB_CELL_LOG: u8 = 3;
+/// Bytes the token bucket holds at least, enough for a jumbo frame.
+const MIN_BURST: u64 = 16 * 1024;
+/// How long a packet may wait in the TBF queue.
+const TBF_LATENCY_MS: u64 = 50;
+/// Root qdisc handle 1: for the TBF.
+pub const TBF_HANDLE: u32 = 0x10000;
+/// Handle ffff: of the ingress qdisc, which its filters hang off.
+pub const INGRESS_HANDLE: u32 = 0xffff0000;
+/// Proxy neighbour entries carry the protocol that made them, like routes.
+pub const NDA_PROTOCOL: u16 = 12;
+
 #[derive(Debug)]
 pub struct Interface {
     pub name: String,
     pub index: u32,
     pub link: u32,
     pub vlan: u16,
+    pub protocol: u16,
+    pub vxlan: Option<Vxlan>,
+    pub master: u32,
+    pub mtu: u32,
+    pub mac: Option<[u8; 6]>,
+    pub up: bool,
+    /// Rate of the root TBF qdisc in bytes per second, if there is one.
+    pub egress_rate: Option<u64>,
+    /// Handle of the root TBF qdisc, `TBF_HANDLE` if it's one of ours.
+    pub egress_handle: Option<u32>,
+    pub ingress_qdisc: bool,
+    /// Rate of the policer on the ingress qdisc in bytes per second.
+    pub ingress_rate: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Vxlan {
+    pub vni: u32,
+    pub local: Option<std::net::IpAddr>,
+    pub port: u16,
+    /// Unicast remote or multicast group, the kernel keeps both in one attribute.
+    pub remote: Option<std::net::IpAddr>,
+    /// Index of the interface to send from, if bound to one.
+    pub link: Option<u32>,
 }
 
 #[derive(Debug)]
+pub struct Vrf {
+    pub name: String,
+    pub index: u32,
+    pub table: u32,
+}
+
+#[derive(Debug, Clone)]
 pub struct Address {
     pub interface: u32,
     pub address: std::net::IpAddr,
@@ -17,19 +74,34 @@ pub struct Address {
     pub message: netlink_packet_route::address::AddressMessage,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Route {
     pub destination: std::net::IpAddr,
     pub destination_prefix_length: u8,
     pub interface: u32,
+    pub kind: u8,
+    pub table: u32,
+    pub metric: u32,
+    pub pref_src: Option<std::net::IpAddr>,
+    pub mtu: Option<u32>,
+    pub scope: u8,
     pub message: netlink_packet_route::route::RouteMessage,
 }
 
+#[derive(Debug, Clone)]
+pub struct Proxy {
+    pub interface: u32,
+    pub address: std::net::IpAddr,
+    pub message: netlink_packet_route::neighbour::NeighbourMessage,
+}
+
 #[derive(Debug)]
 pub struct State {
     pub interfaces: Vec<Interface>,
+    pub vrfs: Vec<Vrf>,
     pub addresses: Vec<Address>,
     pub routes: Vec<Route>,
+    pub proxies: Vec<Proxy>,
 }
 
 async fn get_vlan_interfaces(handle: &rtnetlink::Handle) -> Result<Vec<Interface>, Error> {
@@ -43,7 +115,8 @@ async fn get_vlan_interfaces(handle: &rtnetlink::Handle) -> Result<Vec<Interface
             if let netlink_packet_route::nlas::link::Nla::Info(infos) = nla {
                 for info in infos {
                     if let netlink_packet_route::nlas::link::Info::Kind(
-                        netlink_packet_route::nlas::link::InfoKind::Vlan
+                        netlink_packet_route::nlas::link::InfoKind::Vlan |
+                        netlink_packet_route::nlas::link::InfoKind::Vxlan
                     ) = info {
                         for nla in &msg.nlas {
                             if let netlink_packet_route::nlas::link::Nla::IfName(name) = nla {
@@ -64,7 +137,17 @@ async fn get_vlan_interfaces(handle: &rtnetlink::Handle) -> Result<Vec<Interface
             index: msg.header.index,
             name: String::default(),
             link: 0,
-            vlan: 0
+            vlan: 0,
+            protocol: ETH_P_8021Q,
+            vxlan: None,
+            master: 0,
+            mtu: 0,
+            mac: None,
+            up: msg.header.flags & netlink_packet_route::IFF_UP != 0,
+            egress_rate: None,
+            egress_handle: None,
+            ingress_qdisc: false,
+            ingress_rate: None,
         };
 
         for nla in msg.nlas {
@@ -75,18 +158,64 @@ async fn get_vlan_interfaces(handle: &rtnetlink::Handle) -> Result<Vec<Interface
                             netlink_packet_route::nlas::link::InfoData::Vlan(data)
                         ) = info {
                             for datum in data {
-                                if let netlink_packet_route::nlas::link::InfoVlan::Id(
-                                    vlan
-                                ) = datum {
-                                    inf.vlan = vlan
+                                match datum {
+                                    netlink_packet_route::nlas::link::InfoVlan::Id(vlan) => {
+                                        inf.vlan = vlan
+                                    }
+                                    netlink_packet_route::nlas::link::InfoVlan::Protocol(protocol) => {
+                                        inf.protocol = protocol
+                                    }
+                                    _ => {}
+                                }
+                            }
+                        } else if let netlink_packet_route::nlas::link::Info::Data(
+                            netlink_packet_route::nlas::link::InfoData::Vxlan(data)
+                        ) = info {
+                            let mut vxlan = Vxlan {
+                                vni: 0,
+                                local: None,
+                                port: 0,
+                                remote: None,
+                                link: None,
+                            };
+                            for datum in data {
+                                match datum {
+                                    netlink_packet_route::nlas::link::InfoVxlan::Id(vni) => {
+                                        vxlan.vni = vni
+                                    }
+                                    netlink_packet_route::nlas::link::InfoVxlan::Port(port) => {
+                                        vxlan.port = port
+                                    }
+                                    netlink_packet_route::nlas::link::InfoVxlan::Local(d) |
+                                    netlink_packet_route::nlas::link::InfoVxlan::Local6(d) => {
+                                        vxlan.local = parse_ip(&d)
+                                    }
+                                    netlink_packet_route::nlas::link::InfoVxlan::Group(d) |
+                                    netlink_packet_route::nlas::link::InfoVxlan::Group6(d) => {
+                                        vxlan.remote = parse_ip(&d)
+                                    }
+                                    netlink_packet_route::nlas::link::InfoVxlan::Link(link) => {
+                                        vxlan.link = Some(link)
+                                    }
+                                    _ => {}
                                 }
                             }
+                            inf.vxlan = Some(vxlan);
                         }
                     }
                 }
                 netlink_packet_route::nlas::link::Nla::Link(link) => {
                     inf.link = link;
                 },
+                netlink_packet_route::nlas::link::Nla::Master(master) => {
+                    inf.master = master;
+                },
+                netlink_packet_route::nlas::link::Nla::Mtu(mtu) => {
+                    inf.mtu = mtu;
+                },
+                netlink_packet_route::nlas::link::Nla::Address(mac) => {
+                    inf.mac = mac.try_into().ok();
+                },
                 netlink_packet_route::nlas::link::Nla::IfName(name) => {
                     inf.name = name;
                 },
@@ -101,6 +230,302 @@ async fn get_vlan_interfaces(handle: &rtnetlink::Handle) -> Result<Vec<Interface
 }
 
 
+async fn get_vrfs(handle: &rtnetlink::Handle) -> Result<Vec<Vrf>, Error> {
+    let mut links = handle.link().get().execute();
+    let mut vrfs = vec![];
+
+    while let Some(msg) = links.try_next().await? {
+        let mut vrf = Vrf {
+            index: msg.header.index,
+            name: String::default(),
+            table: 0,
+        };
+        let mut is_vrf = false;
+
+        for nla in msg.nlas {
+            match nla {
+                netlink_packet_route::nlas::link::Nla::Info(infos) => {
+                    for info in infos {
+                        match info {
+                            netlink_packet_route::nlas::link::Info::Kind(
+                                netlink_packet_route::nlas::link::InfoKind::Vrf
+                            ) => {
+                                is_vrf = true;
+                            }
+                            netlink_packet_route::nlas::link::Info::Data(
+                                netlink_packet_route::nlas::link::InfoData::Vrf(data)
+                            ) => {
+                                for datum in data {
+                                    if let netlink_packet_route::nlas::link::InfoVrf::TableId(table) = datum {
+                                        vrf.table = table;
+                                    }
+                                }
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                netlink_packet_route::nlas::link::Nla::IfName(name) => {
+                    vrf.name = name;
+                },
+                _ => {}
+            }
+        }
+
+        if is_vrf && vrf.name.starts_with("vps") {
+            vrfs.push(vrf);
+        }
+    }
+
+    Ok(vrfs)
+}
+
+fn parse_ip(data: &[u8]) -> Option<std::net::IpAddr> {
+    if let Ok(data) = <[u8; 4]>::try_from(data) {
+        Some(std::net::IpAddr::V4(std::net::Ipv4Addr::from(data)))
+    } else if let Ok(data) = <[u8; 16]>::try_from(data) {
+        Some(std::net::IpAddr::V6(std::net::Ipv6Addr::from(data)))
+    } else {
+        None
+    }
+}
+
+/// Find one value in the nested RTA_METRICS attribute.
+fn parse_metric(data: &[u8], kind: u16) -> Option<u32> {
+    let mut data = data;
+    while data.len() >= 4 {
+        let len = u16::from_ne_bytes([data[0], data[1]]) as usize;
+        let nla_kind = u16::from_ne_bytes([data[2], data[3]]);
+        if len < 4 || len > data.len() {
+            break;
+        }
+        if nla_kind == kind && len == 8 {
+            return Some(u32::from_ne_bytes(data[4..8].try_into().unwrap()));
+        }
+        data = &data[((len + 3) & !3).min(data.len())..];
+    }
+    None
+}
+
+/// Build a nested RTA_METRICS attribute holding a single value.
+pub fn emit_metric(kind: u16, value: u32) -> Vec<u8> {
+    let mut data = Vec::with_capacity(8);
+    data.extend_from_slice(&8u16.to_ne_bytes());
+    data.extend_from_slice(&kind.to_ne_bytes());
+    data.extend_from_slice(&value.to_ne_bytes());
+    data
+}
+
+/// Read the value of an attribute netlink-packet-route doesn't know.
+fn nla_value(nla: &netlink_packet_utils::nla::DefaultNla) -> Vec<u8> {
+    let mut value = vec![0; nla.value_len()];
+    nla.emit_value(&mut value);
+    value
+}
+
+/// A 64-bit rate attribute, or the 32-bit rate at `offset` in the parameters
+/// struct when the rate fits in it.
+fn parse_rate(options: &[netlink_packet_utils::nla::DefaultNla], parms: u16, offset: usize, rate64: u16) -> Option<u64> {
+    let mut rate = None;
+    for nla in options {
+        let value = nla_value(nla);
+        if nla.kind() == rate64 && value.len() == 8 {
+            return Some(u64::from_ne_bytes(value.try_into().unwrap()));
+        } else if nla.kind() == parms && value.len() >= offset + 4 {
+            rate = Some(u32::from_ne_bytes(value[offset..offset + 4].try_into().unwrap()) as u64);
+        }
+    }
+    rate
+}
+
+/// A struct tc_ratespec. With the link layer given the kernel doesn't guess
+/// it from the rate table.
+fn emit_ratespec(data: &mut Vec<u8>, cell_log: u8, rate: u64) {
+    data.push(cell_log);
+    data.push(TC_LINKLAYER_ETHERNET);
+    data.extend_from_slice(&0u16.to_ne_bytes());
+    data.extend_from_slice(&0i16.to_ne_bytes());
+    data.extend_from_slice(&0u16.to_ne_bytes());
+    data.extend_from_slice(&(rate.min(u32::MAX as u64) as u32).to_ne_bytes());
+}
+
+/// Time to send `size` bytes at `rate` bytes per second, in the kernel's
+/// 64ns scheduler ticks.
+fn xmit_ticks(rate: u64, size: u64) -> u32 {
+    (size as u128 * 1_000_000_000 / 64 / rate as u128).min(u32::MAX as u128) as u32
+}
+
+fn burst(rate: u64) -> u64 {
+    (rate / 100).clamp(MIN_BURST, u32::MAX as u64)
+}
+
+/// Options of a TBF qdisc shaping to `rate` bytes per second.
+pub fn tbf_options(rate: u64) -> Vec<netlink_packet_route::tc::TcOpt> {
+    let burst = burst(rate);
+    let limit = (rate * TBF_LATENCY_MS / 1000 + burst).min(u32::MAX as u64) as u32;
+
+    // struct tc_tbf_qopt
+    let mut parms = Vec::with_capacity(36);
+    emit_ratespec(&mut parms, 0, rate);
+    emit_ratespec(&mut parms, 0, 0);
+    parms.extend_from_slice(&limit.to_ne_bytes());
+    parms.extend_from_slice(&xmit_ticks(rate, burst).to_ne_bytes());
+    parms.extend_from_slice(&0u32.to_ne_bytes());
+
+    let mut options = vec![
+        netlink_packet_route::tc::TcOpt::Other(
+            netlink_packet_utils::nla::DefaultNla::new(TCA_TBF_PARMS, parms)
+        ),
+        netlink_packet_route::tc::TcOpt::Other(
+            netlink_packet_utils::nla::DefaultNla::new(TCA_TBF_BURST, (burst as u32).to_ne_bytes().to_vec())
+        ),
+    ];
+    if rate > u32::MAX as u64 {
+        options.push(netlink_packet_route::tc::TcOpt::Other(
+            netlink_packet_utils::nla::DefaultNla::new(TCA_TBF_RATE64, rate.to_ne_bytes().to_vec())
+        ));
+    }
+    options
+}
+
+/// A police action dropping whatever exceeds `rate` bytes per second.
+pub fn police_action(rate: u64) -> netlink_packet_route::tc::Action {
+    let burst = burst(rate);
+
+    // struct tc_police
+    let mut parms = Vec::with_capacity(56);
+    parms.extend_from_slice(&0u32.to_ne_bytes());
+    parms.extend_from_slice(&netlink_packet_route::tc::constants::TC_ACT_SHOT.to_ne_bytes());
+    parms.extend_from_slice(&0u32.to_ne_bytes());
+    parms.extend_from_slice(&xmit_ticks(rate, burst).to_ne_bytes());
+    // Only the burst bounds the size of a packet.
+    parms.extend_from_slice(&u32::MAX.to_ne_bytes());
+    emit_ratespec(&mut parms, RTAB_CELL_LOG, rate);
+    emit_ratespec(&mut parms, 0, 0);
+    parms.extend_from_slice(&[0; 12]);
+
+    // The policer won't go without a rate table, even though it only uses
+    // the rate itself.
+    let mut rtab = Vec::with_capacity(TC_RTAB_SIZE * 4);
+    for i in 0..TC_RTAB_SIZE as u64 {
+        rtab.extend_from_slice(&xmit_ticks(rate, (i + 1) << RTAB_CELL_LOG).to_ne_bytes());
+    }
+
+    let mut options = vec![
+        netlink_packet_route::tc::ActOpt::Other(
+            netlink_packet_utils::nla::DefaultNla::new(TCA_POLICE_TBF, parms)
+        ),
+        netlink_packet_route::tc::ActOpt::Other(
+            netlink_packet_utils::nla::DefaultNla::new(TCA_POLICE_RATE, rtab)
+        ),
+    ];
+    if rate > u32::MAX as u64 {
+        options.push(netlink_packet_route::tc::ActOpt::Other(
+            netlink_packet_utils::nla::DefaultNla::new(TCA_POLICE_RATE64, rate.to_ne_bytes().to_vec())
+        ));
+    }
+
+    let mut action = netlink_packet_route::tc::Action::default();
+    action.nlas = vec![
+        netlink_packet_route::tc::ActNla::Kind("police".to_string()),
+        netlink_packet_route::tc::ActNla::Options(options),
+    ];
+    action
+}
+
+/// Fill in the TBF and policer rates of the interfaces from their qdiscs.
+async fn get_rate_limits(handle: &rtnetlink::Handle, interfaces: &mut [Interface]) -> Result<(), Error> {
+    let mut qdiscs = handle.qdisc().get().execute();
+    while let Some(msg) = qdiscs.try_next().await? {
+        let Some(inf) = interfaces.iter_mut().find(|i| i.index as i32 == msg.header.index) else {
+            continue;
+        };
+
+        let mut kind = None;
+        let mut options = vec![];
+        for nla in msg.nlas {
+            match nla {
+                netlink_packet_route::tc::Nla::Kind(k) => kind = Some(k),
+                netlink_packet_route::tc::Nla::Options(opts) => {
+                    options.extend(opts.into_iter().filter_map(|o| match o {
+                        netlink_packet_route::tc::TcOpt::Other(o) => Some(o),
+                        _ => None,
+                    }));
+                }
+                _ => {}
+            }
+        }
+
+        match (msg.header.parent, kind.as_deref()) {
+            (netlink_packet_route::tc::constants::TC_H_ROOT, Some("tbf")) => {
+                inf.egress_rate = parse_rate(&options, TCA_TBF_PARMS, 8, TCA_TBF_RATE64);
+                inf.egress_handle = Some(msg.header.handle);
+            }
+            (netlink_packet_route::tc::constants::TC_H_INGRESS, Some("ingress")) => {
+                inf.ingress_qdisc = true;
+            }
+            _ => {}
+        }
+    }
+
+    // rtnetlink only dumps the filters of the root qdisc.
+    for inf in interfaces.iter_mut().filter(|i| i.ingress_qdisc) {
+        let mut msg = netlink_packet_route::TcMessage::with_index(inf.index as i32);
+        msg.header.parent = INGRESS_HANDLE;
+        let mut req = netlink_packet_core::NetlinkMessage::from(
+            netlink_packet_route::RtnlMessage::GetTrafficFilter(msg)
+        );
+        req.header.flags = netlink_packet_core::NLM_F_REQUEST | netlink_packet_core::NLM_F_DUMP;
+
+        let mut res = handle.clone().request(req)?;
+        while let Some(msg) = res.next().await {
+            let msg = match msg.payload {
+                netlink_packet_core::NetlinkPayload::InnerMessage(
+                    netlink_packet_route::RtnlMessage::NewTrafficFilter(msg)
+                ) => msg,
+                netlink_packet_core::NetlinkPayload::Error(e) => {
+                    return Err(rtnetlink::Error::NetlinkError(e).into());
+                }
+                _ => continue,
+            };
+
+            for nla in msg.nlas {
+                let netlink_packet_route::tc::Nla::Options(opts) = nla else {
+                    continue;
+                };
+                for opt in opts {
+                    let netlink_packet_route::tc::TcOpt::U32(
+                        netlink_packet_route::tc::u32::Nla::Act(actions)
+                    ) = opt else {
+                        continue;
+                    };
+                    for action in actions {
+                        let mut is_police = false;
+                        let mut options = vec![];
+                        for nla in action.nlas {
+                            match nla {
+                                netlink_packet_route::tc::ActNla::Kind(k) => is_police = k == "police",
+                                netlink_packet_route::tc::ActNla::Options(opts) => {
+                                    options.extend(opts.into_iter().filter_map(|o| match o {
+                                        netlink_packet_route::tc::ActOpt::Other(o) => Some(o),
+                                        _ => None,
+                                    }));
+                                }
+                                _ => {}
+                            }
+                        }
+                        if is_police {
+                            inf.ingress_rate = parse_rate(&options, TCA_POLICE_TBF, 28, TCA_POLICE_RATE64);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
 async fn get_addresses(handle: &rtnetlink::Handle) -> Result<Vec<Address>, Error> {
     let mut addresses = vec![];
 
@@ -119,25 +544,22 @@ async fn get_addresses(handle: &rtnetlink::Handle) -> Result<Vec<Address>, Error
 
 
         for nla in msg.nlas {
-            match nla {
-                netlink_packet_route::nlas::address::Nla::Address(d) => {
-                    match msg.header.family as u16 {
-                        netlink_packet_route::constants::AF_INET => {
-                            let data: [u8; 4] = d.try_into().unwrap();
-                            address.address = std::net::IpAddr::V4(
-                                std::net::Ipv4Addr::from(data)
-                            )
-                        }
-                        netlink_packet_route::constants::AF_INET6 => {
-                            let data: [u8; 16] = d.try_into().unwrap();
-                            address.address = std::net::IpAddr::V6(
-                                std::net::Ipv6Addr::from(data)
-                            )
-                        },
-                        _ => {}
+            if let netlink_packet_route::nlas::address::Nla::Address(d) = nla {
+                match msg.header.family as u16 {
+                    netlink_packet_route::constants::AF_INET => {
+                        let data: [u8; 4] = d.try_into().unwrap();
+                        address.address = std::net::IpAddr::V4(
+                            std::net::Ipv4Addr::from(data)
+                        )
                     }
-                },
-                _ => {}
+                    netlink_packet_route::constants::AF_INET6 => {
+                        let data: [u8; 16] = d.try_into().unwrap();
+                        address.address = std::net::IpAddr::V6(
+                            std::net::Ipv6Addr::from(data)
+                        )
+                    },
+                    _ => {}
+                }
             }
         }
 
@@ -152,6 +574,8 @@ async fn get_routes(handle: &rtnetlink::Handle, route_proto: u8) -> Result<Vec<R
     let mut routes = vec![];
     let mut vps_routes = vec![];
 
+    // With the table left unspecified the dump covers every routing table, so
+    // routes in VRF tables show up here too.
     let mut v4_routes = handle.route().get(rtnetlink::IpVersion::V4).execute();
     while let Some(msg) = v4_routes.try_next().await? {
         if msg.header.protocol == route_proto {
@@ -171,6 +595,12 @@ async fn get_routes(handle: &rtnetlink::Handle, route_proto: u8) -> Result<Vec<R
             destination: std::net::IpAddr::from([0, 0, 0, 0]),
             destination_prefix_length: msg.header.destination_prefix_length,
             interface: 0,
+            kind: msg.header.kind,
+            table: msg.header.table as u32,
+            metric: 0,
+            pref_src: None,
+            mtu: None,
+            scope: msg.header.scope,
             message: msg.clone(),
         };
 
@@ -179,6 +609,18 @@ async fn get_routes(handle: &rtnetlink::Handle, route_proto: u8) -> Result<Vec<R
                 netlink_packet_route::nlas::route::Nla::Oif(i) => {
                     route.interface = i;
                 },
+                netlink_packet_route::nlas::route::Nla::Table(t) => {
+                    route.table = t;
+                },
+                netlink_packet_route::nlas::route::Nla::Priority(p) => {
+                    route.metric = p;
+                },
+                netlink_packet_route::nlas::route::Nla::PrefSource(d) => {
+                    route.pref_src = parse_ip(&d);
+                },
+                netlink_packet_route::nlas::route::Nla::Metrics(d) => {
+                    route.mtu = parse_metric(&d, netlink_packet_route::constants::RTAX_MTU);
+                },
                 netlink_packet_route::nlas::route::Nla::Destination(d) => {
                     match msg.header.address_family as u16 {
                         netlink_packet_route::constants::AF_INET => {
@@ -207,6 +649,102 @@ async fn get_routes(handle: &rtnetlink::Handle, route_proto: u8) -> Result<Vec<R
 }
 
 
+async fn get_proxies(handle: &rtnetlink::Handle, route_proto: u8) -> Result<Vec<Proxy>, Error> {
+    let mut proxies = vec![];
+
+    let mut res = handle.neighbours().get().proxies().execute();
+    while let Some(msg) = res.try_next().await? {
+        let mut address = None;
+        let mut protocol = 0;
+        for nla in &msg.nlas {
+            match nla {
+                netlink_packet_route::nlas::neighbour::Nla::Destination(d) => {
+                    address = parse_ip(d);
+                }
+                netlink_packet_route::nlas::neighbour::Nla::Other(o) if o.kind() == NDA_PROTOCOL => {
+                    protocol = nla_value(o).first().copied().unwrap_or_default();
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(address) = address.filter(|_| protocol == route_proto) {
+            proxies.push(Proxy {
+                interface: msg.header.ifindex,
+                address,
+                message: msg,
+            });
+        }
+    }
+
+    Ok(proxies)
+}
+
+#[derive(Debug, Default)]
+pub struct LinkDetails {
+    pub index: u32,
+    pub mac: Option<[u8; 6]>,
+    pub link_local: Option<std::net::Ipv6Addr>,
+}
+
+/// Index, MAC and IPv6 link-local address of every interface by name.
+pub async fn get_link_details(handle: &rtnetlink::Handle) -> Result<std::collections::HashMap<String, LinkDetails>, Error> {
+    let mut details = std::collections::HashMap::new();
+
+    let mut links = handle.link().get().execute();
+    while let Some(msg) = links.try_next().await? {
+        let mut name = None;
+        let mut link = LinkDetails {
+            index: msg.header.index,
+            ..Default::default()
+        };
+        for nla in msg.nlas {
+            match nla {
+                netlink_packet_route::nlas::link::Nla::IfName(n) => name = Some(n),
+                netlink_packet_route::nlas::link::Nla::Address(mac) => link.mac = mac.try_into().ok(),
+                _ => {}
+            }
+        }
+        if let Some(name) = name {
+            details.insert(name, link);
+        }
+    }
+
+    let mut addresses = handle.address().get().execute();
+    while let Some(msg) = addresses.try_next().await? {
+        if msg.header.family as u16 != netlink_packet_route::constants::AF_INET6 ||
+            msg.header.scope != netlink_packet_route::constants::RT_SCOPE_LINK {
+            continue;
+        }
+        let Some(link) = details.values_mut().find(|l| l.index == msg.header.index) else {
+            continue;
+        };
+        for nla in msg.nlas {
+            if let netlink_packet_route::nlas::address::Nla::Address(d) = nla {
+                if let Some(std::net::IpAddr::V6(address)) = parse_ip(&d) {
+                    link.link_local = Some(address);
+                }
+            }
+        }
+    }
+
+    Ok(details)
+}
+
+/// Send a request rtnetlink has no builder for, and wait for it to be acknowledged.
+pub async fn send(handle: &rtnetlink::Handle, message: netlink_packet_route::RtnlMessage, flags: u16) -> Result<(), Error> {
+    let mut req = netlink_packet_core::NetlinkMessage::from(message);
+    req.header.flags = netlink_packet_core::NLM_F_REQUEST | netlink_packet_core::NLM_F_ACK | flags;
+
+    let mut res = handle.clone().request(req)?;
+    while let Some(msg) = res.next().await {
+        if let netlink_packet_core::NetlinkPayload::Error(e) = msg.payload {
+            return Err(rtnetlink::Error::NetlinkError(e).into());
+        }
+    }
+    Ok(())
+}
+
 pub async fn interface_name_to_index(handle: &rtnetlink::Handle, name: &str) -> Result<u32, Error> {
     let mut res = handle.link().get().match_name(name.to_string()).execute();
 
@@ -218,13 +756,18 @@ pub async fn interface_name_to_index(handle: &rtnetlink::Handle, name: &str) ->
 }
 
 pub async fn get_state(handle: &rtnetlink::Handle, route_proto: u8) -> Result<State, Error> {
-    let interfaces = get_vlan_interfaces(handle).await?;
+    let mut interfaces = get_vlan_interfaces(handle).await?;
+    get_rate_limits(handle, &mut interfaces).await?;
+    let vrfs = get_vrfs(handle).await?;
     let addresses = get_addresses(handle).await?;
     let routes = get_routes(handle, route_proto).await?;
+    let proxies = get_proxies(handle, route_proto).await?;
 
     Ok(State {
         interfaces,
+        vrfs,
         addresses,
-        routes
+        routes,
+        proxies,
     })
 }
\ No newline at end of file
@@ -0,0 +1,119 @@
+use crate::{Error, diff, config};
+
+const TABLE: &str = "vps_netlink";
+
+/// Keeps an nftables table dropping traffic from each VPS interface with a
+/// source address that isn't one of the VPS's own.
+pub struct Firewall {
+    nft: std::path::PathBuf,
+    /// The last ruleset loaded, and how nft lists it back.
+    applied: Option<(String, String)>,
+}
+
+impl Firewall {
+    pub fn new(nft: std::path::PathBuf) -> Self {
+        Self {
+            nft,
+            applied: None,
+        }
+    }
+
+    /// Load the ruleset for these interfaces, unless it's what was loaded last
+    /// and the table still looks like it did then, i.e. nobody flushed or
+    /// changed it from outside.
+    pub async fn update(&mut self, interfaces: &[diff::InterfaceState<'_>]) -> Result<(), Error> {
+        let ruleset = make_ruleset(interfaces);
+        if let Some((applied, listing)) = &self.applied {
+            if *applied == ruleset && self.list().await?.as_ref() == Some(listing) {
+                return Ok(());
+            }
+        }
+
+        info!("Loading nftables ruleset");
+        let mut command = tokio::process::Command::new(&self.nft);
+        command.arg("-f");
+        command.arg("-");
+        command.stdin(std::process::Stdio::piped());
+        command.stderr(std::process::Stdio::piped());
+
+        let mut child = command.spawn()?;
+        let mut stdin = child.stdin.take().unwrap();
+        tokio::io::AsyncWriteExt::write_all(&mut stdin, ruleset.as_bytes()).await?;
+        drop(stdin);
+
+        let output = child.wait_with_output().await?;
+        if !output.status.success() {
+            return Err(Error::Nftables(String::from_utf8_lossy(&output.stderr).trim().to_string()));
+        }
+
+        self.applied = self.list().await?.map(|listing| (ruleset, listing));
+        Ok(())
+    }
+
+    /// The table as loaded now, without counter values so it only changes
+    /// along with the rules. `None` if there's no such table.
+    async fn list(&self) -> Result<Option<String>, Error> {
+        let output = tokio::process::Command::new(&self.nft)
+            .args(["--stateless", "list", "table", "inet", TABLE])
+            .output().await?;
+        if !output.status.success() {
+            return Ok(None);
+        }
+        Ok(Some(String::from_utf8_lossy(&output.stdout).into_owned()))
+    }
+}
+
+/// The whole table is deleted and created again in one file, which nft
+/// applies as a single transaction. Declaring it first means the delete
+/// can't fail when the table doesn't exist yet.
+fn make_ruleset(interfaces: &[diff::InterfaceState<'_>]) -> String {
+    use std::fmt::Write;
+
+    let mut ruleset = String::new();
+    writeln!(ruleset, "table inet {}", TABLE).unwrap();
+    writeln!(ruleset, "delete table inet {}", TABLE).unwrap();
+    writeln!(ruleset, "table inet {} {{", TABLE).unwrap();
+    writeln!(ruleset, "  chain antispoof {{").unwrap();
+    writeln!(ruleset, "    type filter hook prerouting priority raw; policy accept;").unwrap();
+
+    for interface in interfaces {
+        let (v4, v6) = allowed_sources(interface.vps);
+        writeln!(
+            ruleset, "    iifname \"{}\" ip saddr != {{ {} }} counter drop",
+            interface.name, v4.join(", ")
+        ).unwrap();
+        writeln!(
+            ruleset, "    iifname \"{}\" ip6 saddr != {{ {} }} counter drop",
+            interface.name, v6.join(", ")
+        ).unwrap();
+    }
+
+    writeln!(ruleset, "  }}").unwrap();
+    writeln!(ruleset, "}}").unwrap();
+    ruleset
+}
+
+/// Besides its own addresses a VPS may use the unspecified address, for DHCP
+/// and duplicate address detection, and link-local ones.
+fn allowed_sources(vps: &config::VPS) -> (Vec<String>, Vec<String>) {
+    let mut v4 = vec![
+        "0.0.0.0".to_string(),
+        "169.254.0.0/16".to_string(),
+        vps.customer_v4().to_string(),
+    ];
+    if let Some(public_v4) = &vps.v4_public {
+        v4.extend(public_v4.as_many().iter().map(|a| a.to_string()));
+    }
+    // nft refuses overlapping elements in a set.
+    v4.sort();
+    v4.dedup();
+
+    let prefix = std::net::Ipv6Addr::from(u128::from(vps.v6_prefix) & !(u128::MAX >> 64));
+    let v6 = vec![
+        "::".to_string(),
+        "fe80::/10".to_string(),
+        format!("{}/64", prefix),
+    ];
+
+    (v4, v6)
+}
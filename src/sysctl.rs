@@ -0,0 +1,25 @@
+use crate::Error;
+
+/// Path of a per-interface sysctl, e.g. `net.ipv6.conf.eth0.proxy_ndp` for
+/// `("ipv6", "eth0", "proxy_ndp")`. Dots in interface names stay as they are.
+pub fn interface_path(family: &str, interface: &str, name: &str) -> std::path::PathBuf {
+    std::path::Path::new("/proc/sys/net")
+        .join(family)
+        .join("conf")
+        .join(interface)
+        .join(name)
+}
+
+/// The current value of a sysctl, `None` if it doesn't exist (yet).
+pub async fn read(path: &std::path::Path) -> Result<Option<String>, Error> {
+    match tokio::fs::read_to_string(path).await {
+        Ok(value) => Ok(Some(value.trim().to_string())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub async fn write(path: &std::path::Path, value: &str) -> Result<(), Error> {
+    tokio::fs::write(path, value).await?;
+    Ok(())
+}